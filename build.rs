@@ -0,0 +1,69 @@
+// Copyright 2022 Stephen Checkoway
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+// The chips, by variant and by the name used by stub/stub.py.
+const CHIPS: [(&str, &str); 9] = [
+    ("Esp8266", "esp8266"),
+    ("Esp32", "esp32"),
+    ("Esp32S2", "esp32s2"),
+    ("Esp32S3", "esp32s3"),
+    ("Esp32C3", "esp32c3"),
+    ("Esp32C2", "esp32c2"),
+    ("Esp32C6", "esp32c6"),
+    ("Esp32H2", "esp32h2"),
+    ("Esp32P4", "esp32p4"),
+];
+
+// With the `bundled-stubs` feature, generate the body of `stub::bundled()`
+// which embeds the stubs generated by stub/stub.py. The build fails if any
+// chip's stub has not been generated.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_BUNDLED_STUBS").is_none() {
+        return;
+    }
+    let stub_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("stub");
+    println!("cargo:rerun-if-changed={}", stub_dir.display());
+
+    let mut code = String::from("match chip {\n");
+    let mut missing = Vec::new();
+    for (variant, name) in CHIPS {
+        let path = stub_dir.join(format!("{name}_stub.bin"));
+        println!("cargo:rerun-if-changed={}", path.display());
+        if !path.is_file() {
+            missing.push(path.display().to_string());
+            continue;
+        }
+        writeln!(
+            code,
+            "    Chip::{variant} => Some(include_bytes!({path:?})),"
+        )
+        .unwrap();
+    }
+    if !missing.is_empty() {
+        panic!(
+            "The bundled-stubs feature requires stubs for every chip; run stub/stub.py or \
+             `espflashtool stub convert` to generate:\n  {}",
+            missing.join("\n  ")
+        );
+    }
+    code.push_str("}\n");
+
+    let out_path = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("bundled_stubs.rs");
+    std::fs::write(out_path, code).unwrap();
+}
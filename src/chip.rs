@@ -26,6 +26,20 @@ pub struct SpiRegs {
 }
 
 impl SpiRegs {
+    // The layout of the SPI1 registers used by the ESP32-C3 and newer chips.
+    const fn esp32c3_layout(base: u32) -> Self {
+        SpiRegs {
+            cmd: base,
+            addr: base + 0x04,
+            user: base + 0x18,
+            user1: base + 0x1C,
+            user2: base + 0x20,
+            mosi_dlen: base + 0x24,
+            miso_dlen: base + 0x28,
+            w0: base + 0x58,
+        }
+    }
+
     #[inline]
     pub fn w(&self, index: usize) -> u32 {
         assert!(index < 16, "SPI data register {index} is out of range");
@@ -33,6 +47,16 @@ impl SpiRegs {
     }
 }
 
+/// The kinds of memory that image segments are loaded into.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum MemoryType {
+    Irom,
+    Drom,
+    Iram,
+    Dram,
+    Other,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Chip {
     Esp8266,
@@ -40,6 +64,10 @@ pub enum Chip {
     Esp32S2,
     Esp32S3,
     Esp32C3,
+    Esp32C2,
+    Esp32C6,
+    Esp32H2,
+    Esp32P4,
 }
 
 impl Chip {
@@ -51,6 +79,12 @@ impl Chip {
             0x000007c6 => Some(Chip::Esp32S2),
             0x6921506F | 0x1B31506F => Some(Chip::Esp32C3),
             0x00000009 => Some(Chip::Esp32S3),
+            0x6F51306F | 0x7C41A06F => Some(Chip::Esp32C2),
+            0x2CE0806F => Some(Chip::Esp32C6),
+            0xD7B73E80 => Some(Chip::Esp32H2),
+            // The ESP32-P4 does not have a useful magic value; it is usually
+            // identified by its chip ID instead.
+            0x0ADDBAD0 => Some(Chip::Esp32P4),
             _ => None,
         }
     }
@@ -61,6 +95,10 @@ impl Chip {
             Chip::Esp32S2 => 2,
             Chip::Esp32S3 => 9,
             Chip::Esp32C3 => 5,
+            Chip::Esp32C2 => 12,
+            Chip::Esp32C6 => 13,
+            Chip::Esp32H2 => 16,
+            Chip::Esp32P4 => 18,
         }
     }
 
@@ -70,10 +108,134 @@ impl Chip {
             2 => Some(Chip::Esp32S2),
             5 => Some(Chip::Esp32C3),
             9 => Some(Chip::Esp32S3),
+            12 => Some(Chip::Esp32C2),
+            13 => Some(Chip::Esp32C6),
+            16 => Some(Chip::Esp32H2),
+            18 => Some(Chip::Esp32P4),
+            _ => None,
+        }
+    }
+
+    /// The possible crystal frequencies in MHz. Chips with more than one
+    /// possibility have their frequency estimated from the UART clock divider.
+    pub fn crystal_frequencies(self) -> &'static [u32] {
+        match self {
+            Chip::Esp8266 | Chip::Esp32 | Chip::Esp32C2 => &[26, 40],
+            Chip::Esp32H2 => &[32],
+            _ => &[40],
+        }
+    }
+
+    /// The UART0 clock divider register, for chips which need it to estimate
+    /// the crystal frequency, and the divider between the crystal and the
+    /// UART clock.
+    pub fn uart_clkdiv_reg(self) -> Option<(u32, u32)> {
+        // https://github.com/espressif/esptool/tree/master/esptool/targets
+        match self {
+            Chip::Esp8266 => Some((0x60000014, 2)),
+            Chip::Esp32 => Some((0x3FF40014, 1)),
+            Chip::Esp32C2 => Some((0x60000014, 1)),
             _ => None,
         }
     }
 
+    /// The `(start, end)` address ranges, end exclusive, of the instruction
+    /// and data buses that are mapped to flash through the cache.
+    pub fn flash_mapped_ranges(self) -> &'static [(u32, u32)] {
+        // The IROM and DROM maps from
+        // https://github.com/espressif/esptool/tree/master/esptool/targets
+        match self {
+            Chip::Esp8266 => &[(0x40200000, 0x40300000)],
+            Chip::Esp32 => &[(0x400D0000, 0x40400000), (0x3F400000, 0x3F800000)],
+            Chip::Esp32S2 => &[(0x40080000, 0x40B80000), (0x3F000000, 0x3F3F0000)],
+            Chip::Esp32S3 => &[(0x42000000, 0x44000000), (0x3C000000, 0x3E000000)],
+            Chip::Esp32C3 => &[(0x42000000, 0x42800000), (0x3C000000, 0x3C800000)],
+            Chip::Esp32C2 => &[(0x42000000, 0x42400000), (0x3C000000, 0x3C400000)],
+            Chip::Esp32C6 | Chip::Esp32H2 => &[(0x42000000, 0x42800000), (0x42800000, 0x43000000)],
+            Chip::Esp32P4 => &[(0x40000000, 0x4C000000)],
+        }
+    }
+
+    /// The `(start, end, type)` address ranges, end exclusive, of the memory
+    /// that image segments are loaded into. Where ranges overlap, the first
+    /// one listed applies.
+    pub(crate) fn memory_map(self) -> &'static [(u32, u32, MemoryType)] {
+        use MemoryType::*;
+        // The DROM, IROM, DRAM, and IRAM ranges of the memory maps from
+        // https://github.com/espressif/esptool/tree/master/esptool/targets
+        match self {
+            Chip::Esp8266 => &[
+                (0x3FFE8000, 0x40000000, Dram),
+                (0x40100000, 0x40108000, Iram),
+                (0x40200000, 0x40300000, Irom),
+            ],
+            Chip::Esp32 => &[
+                (0x3F400000, 0x3F800000, Drom),
+                (0x3FFAE000, 0x40000000, Dram),
+                (0x40080000, 0x400C0000, Iram),
+                (0x400D0000, 0x40400000, Irom),
+            ],
+            Chip::Esp32S2 => &[
+                (0x3F000000, 0x3F3F0000, Drom),
+                (0x3FFB0000, 0x40000000, Dram),
+                (0x40020000, 0x40070000, Iram),
+                (0x40080000, 0x40B80000, Irom),
+            ],
+            Chip::Esp32S3 => &[
+                (0x3C000000, 0x3E000000, Drom),
+                (0x3FC88000, 0x3FD00000, Dram),
+                (0x40370000, 0x403E0000, Iram),
+                (0x42000000, 0x44000000, Irom),
+            ],
+            Chip::Esp32C3 => &[
+                (0x3C000000, 0x3C800000, Drom),
+                (0x3FC80000, 0x3FCE0000, Dram),
+                (0x4037C000, 0x403E0000, Iram),
+                (0x42000000, 0x42800000, Irom),
+            ],
+            Chip::Esp32C2 => &[
+                (0x3C000000, 0x3C400000, Drom),
+                (0x3FCA0000, 0x3FCE0000, Dram),
+                (0x4037C000, 0x403C0000, Iram),
+                (0x42000000, 0x42400000, Irom),
+            ],
+            // The instruction and data buses share the internal RAM.
+            Chip::Esp32C6 => &[
+                (0x42800000, 0x43000000, Drom),
+                (0x40800000, 0x40880000, Dram),
+                (0x42000000, 0x42800000, Irom),
+            ],
+            Chip::Esp32H2 => &[
+                (0x42800000, 0x43000000, Drom),
+                (0x40800000, 0x40850000, Dram),
+                (0x42000000, 0x42800000, Irom),
+            ],
+            // The instruction and data buses share flash as well.
+            Chip::Esp32P4 => &[
+                (0x40000000, 0x4C000000, Drom),
+                (0x4FF00000, 0x4FFC0000, Dram),
+            ],
+        }
+    }
+
+    /// The type of memory at `addr`.
+    pub(crate) fn memory_type(self, addr: u32) -> MemoryType {
+        self.memory_map()
+            .iter()
+            .find(|&&(start, end, _)| start <= addr && addr < end)
+            .map_or(MemoryType::Other, |&(_, _, memory_type)| memory_type)
+    }
+
+    /// The flash offset at which the ROM expects to find the second stage
+    /// bootloader.
+    pub fn bootloader_flash_offset(self) -> u32 {
+        match self {
+            Chip::Esp32 | Chip::Esp32S2 => 0x1000,
+            Chip::Esp32P4 => 0x2000,
+            _ => 0x0,
+        }
+    }
+
     pub fn spi_regs(self) -> SpiRegs {
         match self {
             // SPI0
@@ -114,16 +276,10 @@ impl Chip {
                 w0: 0x3F402058,
             },
             // SPI1 on the ESP32-C3; the same registers seem to work on the ESP32-S3.
-            Chip::Esp32S3 | Chip::Esp32C3 => SpiRegs {
-                cmd: 0x60002000,
-                addr: 0x60002004,
-                user: 0x60002018,
-                user1: 0x6000201C,
-                user2: 0x60002020,
-                mosi_dlen: 0x60002024,
-                miso_dlen: 0x60002028,
-                w0: 0x60002058,
-            },
+            Chip::Esp32S3 | Chip::Esp32C3 | Chip::Esp32C2 => SpiRegs::esp32c3_layout(0x60002000),
+            // SPI1 has the same layout as on the ESP32-C3 but at a different base.
+            Chip::Esp32C6 | Chip::Esp32H2 => SpiRegs::esp32c3_layout(0x60003000),
+            Chip::Esp32P4 => SpiRegs::esp32c3_layout(0x5008D000),
         }
     }
 }
@@ -138,6 +294,10 @@ impl TryFrom<&str> for Chip {
             "esp32s2" => Chip::Esp32S2,
             "esp32s3" => Chip::Esp32S3,
             "esp32c3" => Chip::Esp32C3,
+            "esp32c2" => Chip::Esp32C2,
+            "esp32c6" => Chip::Esp32C6,
+            "esp32h2" => Chip::Esp32H2,
+            "esp32p4" => Chip::Esp32P4,
             _ => return Err(()),
         })
     }
@@ -151,6 +311,10 @@ impl std::fmt::Display for Chip {
             Chip::Esp32S2 => "ESP32-S2",
             Chip::Esp32S3 => "ESP32-S3",
             Chip::Esp32C3 => "ESP32-C3",
+            Chip::Esp32C2 => "ESP32-C2",
+            Chip::Esp32C6 => "ESP32-C6",
+            Chip::Esp32H2 => "ESP32-H2",
+            Chip::Esp32P4 => "ESP32-P4",
         })
     }
 }
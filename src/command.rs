@@ -106,6 +106,9 @@ pub enum Command {
         size: u32,
     },
 
+    // Commands supported by the ESP32-S2 and later bootloaders.
+    GetSecurityInfo,
+
     // Stub-only commands.
     EraseFlash,
     EraseRegion {
@@ -140,6 +143,7 @@ impl Command {
             0x11 => "FlashDeflData",
             0x12 => "FlashDeflEnd",
             0x13 => "SpiFlashMD5",
+            0x14 => "GetSecurityInfo",
             0xD0 => "EraseFlash",
             0xD1 => "EraseRegion",
             0xD2 => "ReadFlash",
@@ -183,6 +187,7 @@ impl Command {
             Command::FlashDeflData { .. } => 0x11,
             Command::FlashDeflEnd { .. } => 0x12,
             Command::SpiFlashMD5 { .. } => 0x13,
+            Command::GetSecurityInfo => 0x14,
             Command::EraseFlash => 0xD0,
             Command::EraseRegion { .. } => 0xD1,
             Command::ReadFlash { .. } => 0xD2,
@@ -306,14 +311,19 @@ impl From<u8> for CommandError {
 // loader) we're talking to for the first SYNC command. However, for all of
 // the commands and chips this flasher knows about, the length of the data
 // itself determines the size. Most responses have no data other than the
-// status/error. Command::SpiFlashMd5 is the only command which has any data
-// and that is only supported by the ESP32 and later.
+// status/error. Command::SpiFlashMd5 and Command::GetSecurityInfo are the
+// only commands which have any data and they are only supported by the ESP32
+// and later.
 #[inline]
 fn status_size(data_len: u16) -> usize {
     match data_len {
         2 => 2,  // [stub, ESP8266]: status, error
         4 => 4,  // [ESP32]:         status, error, 0, 0
+        14 => 2, // [stub]:          security info (ESP32-S2), status, error
+        16 => 4, // [ESP32-S2]:      security info, status, error, 0, 0
         18 => 2, // [stub]:          MD5 hash (bin), status, error
+        22 => 2, // [stub]:          security info with chip ID, status, error
+        24 => 4, // [ESP32-C3+]:     security info with chip ID, status, error, 0, 0
         36 => 4, // [ESP32]:         MD5 hash (hex), status, error, 0, 0
         _ => 2,  // This doesn't occur with the current commands and chips.
     }
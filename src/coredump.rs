@@ -0,0 +1,363 @@
+// Copyright 2022 Stephen Checkoway
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Core dumps written to the coredump partition by ESP-IDF.
+//!
+//! The partition holds a header, an ELF core file, and a CRC-32 or SHA-256
+//! checksum of the header and the ELF file. Each task has an `NT_PRSTATUS`
+//! note holding its registers; the task's TCB and stack are `PT_LOAD`
+//! segments.
+
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::elf::{self, ElfSegment, EM_RISCV, EM_XTENSA, PT_LOAD, PT_NOTE};
+use crate::{from_le, Chip, Error, Result};
+
+// https://github.com/espressif/esp-idf/blob/master/components/espcoredump/include_core_dump/esp_core_dump_types.h
+const VERSION_ELF: u32 = 1;
+const NT_PRSTATUS: u32 = 1;
+// The registers follow pr_info, pr_cursig, and the other fields of the
+// prstatus structure. The pr_pid field holds the address of the TCB.
+const PRSTATUS_PID_OFFSET: usize = 24;
+const PRSTATUS_REGS_OFFSET: usize = 72;
+// The offset of pcTaskName in the FreeRTOS TCB.
+const TCB_NAME_OFFSET: u32 = 52;
+const TCB_NAME_LEN: usize = 16;
+
+#[rustfmt::skip]
+const XTENSA_REGS: [&str; 8] = [
+    "pc", "ps", "lbeg", "lend", "lcount", "sar", "windowstart", "windowbase",
+];
+// The address registers follow 56 reserved words.
+const XTENSA_AR_INDEX: usize = 64;
+#[rustfmt::skip]
+const XTENSA_AR: [&str; 16] = [
+    "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+    "a8", "a9", "a10", "a11", "a12", "a13", "a14", "a15",
+];
+#[rustfmt::skip]
+const RISCV_REGS: [&str; 32] = [
+    "pc", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+fn format_error(msg: &str) -> Error {
+    Error::FormatError(msg.into())
+}
+
+#[derive(Debug, Clone)]
+pub struct CoreDump {
+    /// The chip in the top 16 bits, and the format and minor version in the
+    /// next two bytes.
+    pub version: u32,
+    pub tasks_num: u32,
+    /// The chip revision is only recorded by newer versions.
+    pub chip_rev: Option<u32>,
+    /// The ELF core file.
+    pub elf: Vec<u8>,
+}
+
+impl CoreDump {
+    /// Parse the contents of the coredump partition and verify the checksum.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < 8 {
+            return Err(format_error("Core dump too short"));
+        }
+        let data_len = from_le(&data[0..4]) as usize;
+        let version = from_le(&data[4..8]);
+        if (version >> 8) & 0xFF != VERSION_ELF {
+            return Err(Error::FormatError(format!(
+                "Unsupported core dump version 0x{version:08X}; only the ELF format is supported"
+            )));
+        }
+        // Odd minor versions use SHA-256; versions 2 and later add the chip
+        // revision to the header.
+        let (header_len, checksum_len) = match version & 0xFF {
+            0 => (20, 4),
+            1 => (20, 32),
+            2 => (24, 4),
+            3 => (24, 32),
+            _ => {
+                return Err(Error::FormatError(format!(
+                    "Unsupported core dump version 0x{version:08X}"
+                )))
+            }
+        };
+        if data_len > data.len() || data_len < header_len + checksum_len {
+            return Err(format_error("Invalid core dump length"));
+        }
+        let (contents, checksum) = data[..data_len].split_at(data_len - checksum_len);
+        let valid = if checksum_len == 4 {
+            crc32fast::hash(contents) == from_le(checksum)
+        } else {
+            Sha256::digest(contents).as_slice() == checksum
+        };
+        if !valid {
+            return Err(format_error("Core dump checksum mismatch"));
+        }
+        Ok(CoreDump {
+            version,
+            tasks_num: from_le(&data[8..12]),
+            chip_rev: (header_len == 24).then(|| from_le(&data[20..24])),
+            elf: contents[header_len..].to_vec(),
+        })
+    }
+
+    pub fn chip(&self) -> Option<Chip> {
+        Chip::try_from_image_chip_id((self.version >> 16) as u16)
+    }
+
+    /// The tasks and their registers.
+    pub fn tasks(&self) -> Result<Vec<CoreDumpTask>> {
+        let (machine, segments) = elf::core_segments(&self.elf)?;
+        let (names, ar_names): (&[&str], &[&str]) = match machine {
+            EM_XTENSA => (&XTENSA_REGS, &XTENSA_AR),
+            EM_RISCV => (&RISCV_REGS, &[]),
+            _ => return Err(format_error("Unsupported core dump architecture")),
+        };
+        let mut tasks = Vec::new();
+        for segment in segments.iter().filter(|seg| seg.p_type == PT_NOTE) {
+            for (note_type, desc) in notes(segment.data)? {
+                if note_type != NT_PRSTATUS {
+                    continue;
+                }
+                let words = |start: usize, count: usize| -> Result<Vec<u32>> {
+                    let start = PRSTATUS_REGS_OFFSET + 4 * start;
+                    desc.get(start..start + 4 * count)
+                        .map(|regs| regs.chunks(4).map(from_le).collect())
+                        .ok_or_else(|| format_error("Core dump register note too short"))
+                };
+                let mut registers: Vec<(&'static str, u32)> =
+                    names.iter().copied().zip(words(0, names.len())?).collect();
+                if !ar_names.is_empty() {
+                    let ar = words(XTENSA_AR_INDEX, ar_names.len())?;
+                    registers.extend(ar_names.iter().copied().zip(ar));
+                }
+                let tcb = from_le(&desc[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4]);
+                tasks.push(CoreDumpTask {
+                    tcb,
+                    name: task_name(&segments, tcb),
+                    registers,
+                });
+            }
+        }
+        Ok(tasks)
+    }
+}
+
+impl fmt::Display for CoreDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Core dump version {}.{}",
+            (self.version >> 8) & 0xFF,
+            self.version & 0xFF
+        )?;
+        if let Some(chip) = self.chip() {
+            write!(f, " for {chip}")?;
+        }
+        if let Some(chip_rev) = self.chip_rev {
+            write!(f, " (revision {chip_rev})")?;
+        }
+        write!(f, " with {} tasks", self.tasks_num)
+    }
+}
+
+// The type and contents of each note in a PT_NOTE segment.
+fn notes(mut data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    let align = |len: usize| (len + 3) & !3;
+    let mut notes = Vec::new();
+    while data.len() >= 12 {
+        let name_len = from_le(&data[0..4]) as usize;
+        let desc_len = from_le(&data[4..8]) as usize;
+        let note_type = from_le(&data[8..12]);
+        let desc_start = 12 + align(name_len);
+        let desc_end = desc_start + desc_len;
+        if desc_end > data.len() {
+            return Err(format_error("Invalid core dump note"));
+        }
+        notes.push((note_type, &data[desc_start..desc_end]));
+        data = data.get(align(desc_end)..).unwrap_or_default();
+    }
+    Ok(notes)
+}
+
+// Read the task name from the TCB if it was dumped.
+fn task_name(segments: &[ElfSegment<'_>], tcb: u32) -> String {
+    let start = tcb.wrapping_add(TCB_NAME_OFFSET);
+    for segment in segments.iter().filter(|seg| seg.p_type == PT_LOAD) {
+        let offset = start.wrapping_sub(segment.addr) as usize;
+        if start < segment.addr || offset + TCB_NAME_LEN > segment.data.len() {
+            continue;
+        }
+        let name = &segment.data[offset..offset + TCB_NAME_LEN];
+        let len = name.iter().position(|&b| b == 0).unwrap_or(TCB_NAME_LEN);
+        return String::from_utf8_lossy(&name[..len]).into_owned();
+    }
+    String::from("?")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreDumpTask {
+    /// The address of the task control block.
+    pub tcb: u32,
+    pub name: String,
+    pub registers: Vec<(&'static str, u32)>,
+}
+
+impl CoreDumpTask {
+    pub fn register(&self, name: &str) -> Option<u32> {
+        self.registers
+            .iter()
+            .find(|(reg, _)| *reg == name)
+            .map(|&(_, value)| value)
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.register("pc").unwrap_or_default()
+    }
+
+    /// The stack pointer is `sp` on RISC-V and `a1` on Xtensa.
+    pub fn sp(&self) -> u32 {
+        self.register("sp")
+            .or_else(|| self.register("a1"))
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for CoreDumpTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Task \"{}\" (TCB 0x{:08X}): PC 0x{:08X} SP 0x{:08X}",
+            self.name,
+            self.tcb,
+            self.pc(),
+            self.sp()
+        )?;
+        for regs in self.registers.chunks(4) {
+            for (name, value) in regs {
+                write!(f, " {name:>11} 0x{value:08X}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::partition::{EspPartitionTable, PartitionEntry};
+    use crate::sim::test_util::connect;
+    use binrw::BinWrite;
+
+    const TCB: u32 = 0x3FC8_0000;
+
+    // A core dump for `chip` with a single task whose registers, in note
+    // order, are 0x1000 times their index.
+    fn coredump(chip: Chip, machine: u16, num_regs: usize) -> Vec<u8> {
+        let mut desc = vec![0u8; PRSTATUS_REGS_OFFSET + 4 * num_regs];
+        desc[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4].copy_from_slice(&TCB.to_le_bytes());
+        for (idx, reg) in desc[PRSTATUS_REGS_OFFSET..].chunks_mut(4).enumerate() {
+            reg.copy_from_slice(&(0x1000 * idx as u32).to_le_bytes());
+        }
+        let mut note = Vec::new();
+        for value in [5, desc.len() as u32, NT_PRSTATUS] {
+            note.extend(u32::to_le_bytes(value));
+        }
+        note.extend(b"CORE\0\0\0\0");
+        note.extend(desc);
+        let mut tcb = vec![0u8; TCB_NAME_OFFSET as usize];
+        tcb.extend(b"main\0\0\0\0\0\0\0\0\0\0\0\0");
+        let elf = elf::build_elf(
+            elf::ET_CORE,
+            machine,
+            &[(PT_NOTE, 0, note), (PT_LOAD, TCB, tcb)],
+            &[],
+        );
+
+        let data_len = 24 + elf.len() as u32 + 4;
+        let version = (chip.image_chip_id() as u32) << 16 | (VERSION_ELF << 8) | 2;
+        let mut data = Vec::new();
+        for value in [data_len, version, 1, 0x15C, 2, 3] {
+            data.extend(u32::to_le_bytes(value));
+        }
+        data.extend(elf);
+        data.extend(crc32fast::hash(&data).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_xtensa_coredump() -> Result<()> {
+        let data = coredump(Chip::Esp32, EM_XTENSA, XTENSA_AR_INDEX + XTENSA_AR.len());
+        let coredump = CoreDump::from_bytes(&data)?;
+        assert_eq!(coredump.chip(), Some(Chip::Esp32));
+        let tasks = coredump.tasks()?;
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].name, "main");
+        assert_eq!(tasks[0].registers.len(), 24);
+        assert_eq!(tasks[0].pc(), 0);
+        assert_eq!(tasks[0].register("windowbase"), Some(0x7000));
+        assert_eq!(tasks[0].sp(), 0x41000);
+        assert_eq!(tasks[0].register("a15"), Some(0x4F000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_coredump() -> Result<()> {
+        let data = coredump(Chip::Esp32C3, EM_RISCV, 33);
+        let coredump = CoreDump::from_bytes(&data)?;
+        assert_eq!(coredump.chip(), Some(Chip::Esp32C3));
+        assert_eq!(coredump.chip_rev, Some(3));
+        assert_eq!(&coredump.elf[..4], b"\x7FELF");
+        let tasks = coredump.tasks()?;
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].name, "main");
+        assert_eq!(tasks[0].tcb, TCB);
+        assert_eq!(tasks[0].pc(), 0);
+        assert_eq!(tasks[0].sp(), 0x2000);
+        assert_eq!(tasks[0].register("t6"), Some(0x1F000));
+
+        let mut corrupt = data.clone();
+        corrupt[30] ^= 1;
+        assert!(CoreDump::from_bytes(&corrupt).is_err());
+
+        // Read it from flash.
+        let table = EspPartitionTable {
+            entries: vec![
+                PartitionEntry::Partition {
+                    type_: 1,
+                    subtype: 3,
+                    offset: 0x10000,
+                    size: 0x10000,
+                    label: *b"coredump\0\0\0\0\0\0\0\0",
+                    flags: 0,
+                },
+                PartitionEntry::Hash { digest: [0; 16] },
+            ],
+        };
+        let mut table_data = Vec::new();
+        table.write_to(&mut std::io::Cursor::new(&mut table_data))?;
+        let (device, mut flasher) = connect(Chip::Esp32C3, true)?;
+        device.set_flash(0x8000, &table_data);
+        device.set_flash(0x10000, &data);
+        assert_eq!(flasher.read_coredump()?.elf, coredump.elf);
+        Ok(())
+    }
+}
@@ -0,0 +1,602 @@
+// Copyright 2022 Stephen Checkoway
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading and decoding eFuses.
+//!
+//! The eFuses are organized into blocks of 32-bit words which the loader can
+//! read as registers. Each chip has its own layout of fields within the
+//! blocks. The tables here come from the eFuse tables in the ESP-IDF and
+//! esptool.
+
+use std::fmt::{self, Write};
+
+use serde::{Serialize, Serializer};
+
+use crate::security::key_purpose_name;
+use crate::{Chip, Result};
+
+struct Block {
+    address: u32,
+    words: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Bool,
+    Uint,
+    KeyPurpose,
+    // Six bytes stored in two words in the order used by the ESP32 and later.
+    Mac,
+    // The ESP8266 stores part of its MAC and determines the OUI from other
+    // bits.
+    Esp8266Mac,
+    Bytes,
+}
+
+struct Field {
+    name: &'static str,
+    block: usize,
+    word: usize,
+    bit: u32,
+    width: u32,
+    kind: Kind,
+    description: &'static str,
+}
+
+const fn field(
+    name: &'static str,
+    block: usize,
+    word: usize,
+    bit: u32,
+    width: u32,
+    kind: Kind,
+    description: &'static str,
+) -> Field {
+    Field {
+        name,
+        block,
+        word,
+        bit,
+        width,
+        kind,
+        description,
+    }
+}
+
+const fn flag(
+    name: &'static str,
+    block: usize,
+    word: usize,
+    bit: u32,
+    desc: &'static str,
+) -> Field {
+    field(name, block, word, bit, 1, Kind::Bool, desc)
+}
+
+const fn uint(
+    name: &'static str,
+    block: usize,
+    word: usize,
+    bit: u32,
+    width: u32,
+    desc: &'static str,
+) -> Field {
+    field(name, block, word, bit, width, Kind::Uint, desc)
+}
+
+const MAC_DESC: &str = "MAC address";
+const USER_DATA_DESC: &str = "User data";
+const PKG_DESC: &str = "Package version";
+const MAJOR_DESC: &str = "Major chip revision";
+const MINOR_DESC: &str = "Minor chip revision";
+
+// https://github.com/espressif/esptool/blob/master/esptool/targets/esp8266.py
+const ESP8266_BLOCKS: &[Block] = &[Block {
+    address: 0x3FF0_0050,
+    words: 4,
+}];
+
+#[rustfmt::skip]
+const ESP8266_FIELDS: &[Field] = &[
+    field("MAC", 0, 0, 0, 48, Kind::Esp8266Mac, MAC_DESC),
+    uint("EFUSE_DATA0", 0, 0, 0, 32, "eFuse word 0"),
+    uint("EFUSE_DATA1", 0, 1, 0, 32, "eFuse word 1"),
+    uint("EFUSE_DATA2", 0, 2, 0, 32, "eFuse word 2"),
+    uint("EFUSE_DATA3", 0, 3, 0, 32, "eFuse word 3"),
+    uint("FLASH_SIZE", 0, 3, 26, 2, "Flash size (0 = 2 MB, 1 = 4 MB)"),
+];
+
+// https://github.com/espressif/esp-idf/blob/master/components/efuse/esp32/esp_efuse_table.csv
+const ESP32_BLOCKS: &[Block] = &[
+    Block {
+        address: 0x3FF5_A000,
+        words: 7,
+    },
+    Block {
+        address: 0x3FF5_A078,
+        words: 8,
+    },
+];
+
+#[rustfmt::skip]
+const ESP32_FIELDS: &[Field] = &[
+    uint("WR_DIS",                0, 0,  0, 16, "Write disable bits"),
+    uint("RD_DIS",                0, 0, 16,  4, "Read disable bits"),
+    uint("FLASH_CRYPT_CNT",       0, 0, 20,  7, "Flash encryption counter"),
+    field("MAC",                  0, 1,  0, 48, Kind::Mac, MAC_DESC),
+    uint("MAC_CRC",               0, 2, 16,  8, "CRC8 of the MAC address"),
+    flag("DISABLE_APP_CPU",       0, 3,  0,     "Disables the APP CPU"),
+    flag("DISABLE_BT",            0, 3,  1,     "Disables Bluetooth"),
+    uint("CHIP_PACKAGE_4BIT",     0, 3,  2,  1, "Package version bit 3"),
+    uint("CHIP_PACKAGE",          0, 3,  9,  3, PKG_DESC),
+    flag("CHIP_VER_REV1",         0, 3, 15,     "Chip revision bit 0"),
+    flag("CHIP_VER_REV2",         0, 5, 20,     "Chip revision bit 1"),
+    uint("WAFER_VERSION_MINOR",   0, 5, 24,  2, MINOR_DESC),
+    uint("FLASH_CRYPT_CONFIG",    0, 5, 28,  4, "Flash encryption config"),
+    uint("CODING_SCHEME",         0, 6,  0,  2, "eFuse coding scheme"),
+    flag("CONSOLE_DEBUG_DISABLE", 0, 6,  2,     "Disables the ROM BASIC console"),
+    flag("ABS_DONE_0",            0, 6,  4,     "Secure boot V1 enabled"),
+    flag("ABS_DONE_1",            0, 6,  5,     "Secure boot V2 enabled"),
+    flag("JTAG_DISABLE",          0, 6,  6,     "Disables JTAG"),
+    flag("DISABLE_DL_ENCRYPT",    0, 6,  7,     "Disables flash encryption in download mode"),
+    flag("DISABLE_DL_DECRYPT",    0, 6,  8,     "Disables flash decryption in download mode"),
+    flag("DISABLE_DL_CACHE",      0, 6,  9,     "Disables the flash cache in download mode"),
+    field("BLOCK3",               1, 0,  0, 256, Kind::Bytes, USER_DATA_DESC),
+];
+
+// The ESP32-S2 and later (except the ESP32-C2) share a layout: BLOCK0 holds
+// the system configuration, BLOCK1 the MAC and chip revision, and BLOCK3 the
+// user data. Only the chip revision fields differ between chips.
+const fn blocks_s2_layout(base: u32) -> [Block; 3] {
+    [
+        Block {
+            address: base + 0x2C,
+            words: 6,
+        },
+        Block {
+            address: base + 0x44,
+            words: 6,
+        },
+        Block {
+            address: base + 0x7C,
+            words: 8,
+        },
+    ]
+}
+
+const ESP32S2_BLOCKS: &[Block] = &blocks_s2_layout(0x3F41_A000);
+const ESP32S3_BLOCKS: &[Block] = &blocks_s2_layout(0x6000_7000);
+const ESP32C3_BLOCKS: &[Block] = &blocks_s2_layout(0x6000_8800);
+const ESP32C6_BLOCKS: &[Block] = &blocks_s2_layout(0x600B_0800);
+const ESP32H2_BLOCKS: &[Block] = &blocks_s2_layout(0x600B_0800);
+const ESP32P4_BLOCKS: &[Block] = &blocks_s2_layout(0x5012_D000);
+
+#[rustfmt::skip]
+const S2_LAYOUT_FIELDS: &[Field] = &[
+    uint("WR_DIS",                        0, 0,  0, 32, "Write disable bits"),
+    uint("RD_DIS",                        0, 1,  0,  7, "Read disable bits for the key blocks"),
+    uint("SPI_BOOT_CRYPT_CNT",            0, 2, 18,  3, "Flash encryption counter"),
+    flag("SECURE_BOOT_KEY_REVOKE0",       0, 2, 21,     "Revokes secure boot key 0"),
+    flag("SECURE_BOOT_KEY_REVOKE1",       0, 2, 22,     "Revokes secure boot key 1"),
+    flag("SECURE_BOOT_KEY_REVOKE2",       0, 2, 23,     "Revokes secure boot key 2"),
+    field("KEY_PURPOSE_0",                0, 2, 24,  4, Kind::KeyPurpose, "Purpose of BLOCK_KEY0"),
+    field("KEY_PURPOSE_1",                0, 2, 28,  4, Kind::KeyPurpose, "Purpose of BLOCK_KEY1"),
+    field("KEY_PURPOSE_2",                0, 3,  0,  4, Kind::KeyPurpose, "Purpose of BLOCK_KEY2"),
+    field("KEY_PURPOSE_3",                0, 3,  4,  4, Kind::KeyPurpose, "Purpose of BLOCK_KEY3"),
+    field("KEY_PURPOSE_4",                0, 3,  8,  4, Kind::KeyPurpose, "Purpose of BLOCK_KEY4"),
+    field("KEY_PURPOSE_5",                0, 3, 12,  4, Kind::KeyPurpose, "Purpose of BLOCK_KEY5"),
+    flag("SECURE_BOOT_EN",                0, 3, 20,     "Secure boot enabled"),
+    flag("SECURE_BOOT_AGGRESSIVE_REVOKE", 0, 3, 21,     "Aggressive secure boot key revocation"),
+    flag("DIS_DOWNLOAD_MODE",             0, 4,  0,     "Disables download mode"),
+    field("MAC",                          1, 0,  0, 48, Kind::Mac, MAC_DESC),
+    field("BLOCK_USR_DATA",               2, 0,  0, 256, Kind::Bytes, USER_DATA_DESC),
+];
+
+#[rustfmt::skip]
+const ESP32S2_REVISION_FIELDS: &[Field] = &[
+    uint("WAFER_VERSION_MAJOR",    1, 3, 18, 2, MAJOR_DESC),
+    uint("WAFER_VERSION_MINOR_HI", 1, 3, 20, 1, "Minor chip revision bit 3"),
+    uint("WAFER_VERSION_MINOR_LO", 1, 4,  4, 3, "Minor chip revision bits 0-2"),
+    uint("PKG_VERSION",            1, 4,  0, 4, PKG_DESC),
+];
+
+// The ESP32-S3 and ESP32-C3 use the same layout.
+#[rustfmt::skip]
+const ESP32C3_REVISION_FIELDS: &[Field] = &[
+    uint("WAFER_VERSION_MAJOR",    1, 5, 24, 2, MAJOR_DESC),
+    uint("WAFER_VERSION_MINOR_HI", 1, 5, 23, 1, "Minor chip revision bit 3"),
+    uint("WAFER_VERSION_MINOR_LO", 1, 3, 18, 3, "Minor chip revision bits 0-2"),
+    uint("PKG_VERSION",            1, 3, 21, 3, PKG_DESC),
+];
+
+#[rustfmt::skip]
+const ESP32C6_REVISION_FIELDS: &[Field] = &[
+    uint("WAFER_VERSION_MAJOR", 1, 3, 22, 2, MAJOR_DESC),
+    uint("WAFER_VERSION_MINOR", 1, 3, 18, 4, MINOR_DESC),
+    uint("PKG_VERSION",         1, 3, 24, 3, PKG_DESC),
+];
+
+#[rustfmt::skip]
+const ESP32H2_REVISION_FIELDS: &[Field] = &[
+    uint("WAFER_VERSION_MAJOR", 1, 3, 21, 2, MAJOR_DESC),
+    uint("WAFER_VERSION_MINOR", 1, 3, 18, 3, MINOR_DESC),
+    uint("PKG_VERSION",         1, 4, 0, 3, PKG_DESC),
+];
+
+#[rustfmt::skip]
+const ESP32P4_REVISION_FIELDS: &[Field] = &[
+    uint("WAFER_VERSION_MAJOR", 1, 2,  4, 2, MAJOR_DESC),
+    uint("WAFER_VERSION_MINOR", 1, 2,  0, 4, MINOR_DESC),
+    uint("PKG_VERSION",         1, 2, 20, 3, PKG_DESC),
+];
+
+// The ESP32-C2 has fewer eFuses and a single key block.
+// https://github.com/espressif/esp-idf/blob/master/components/efuse/esp32c2/esp_efuse_table.csv
+const ESP32C2_BLOCKS: &[Block] = &[
+    Block {
+        address: 0x6000_882C,
+        words: 2,
+    },
+    Block {
+        address: 0x6000_8840,
+        words: 8,
+    },
+];
+
+#[rustfmt::skip]
+const ESP32C2_FIELDS: &[Field] = &[
+    uint("WR_DIS",              0, 0,  0, 8, "Write disable bits"),
+    uint("RD_DIS",              0, 1,  0, 2, "Read disable bits for the key block"),
+    uint("SPI_BOOT_CRYPT_CNT",  0, 1,  7, 3, "Flash encryption counter"),
+    flag("XTS_KEY_LENGTH_256",  0, 1, 10,    "Use the whole key block as a 256-bit key"),
+    flag("DIS_DOWNLOAD_MODE",   0, 1, 14,    "Disables download mode"),
+    flag("SECURE_BOOT_EN",      0, 1, 21,    "Secure boot enabled"),
+    field("MAC",                1, 0,  0, 48, Kind::Mac, MAC_DESC),
+    uint("WAFER_VERSION_MINOR", 1, 1, 16, 4, MINOR_DESC),
+    uint("WAFER_VERSION_MAJOR", 1, 1, 20, 2, MAJOR_DESC),
+    uint("PKG_VERSION",         1, 1, 22, 3, PKG_DESC),
+];
+
+fn tables(chip: Chip) -> (&'static [Block], &'static [Field], &'static [Field]) {
+    match chip {
+        Chip::Esp8266 => (ESP8266_BLOCKS, ESP8266_FIELDS, &[]),
+        Chip::Esp32 => (ESP32_BLOCKS, ESP32_FIELDS, &[]),
+        Chip::Esp32S2 => (ESP32S2_BLOCKS, S2_LAYOUT_FIELDS, ESP32S2_REVISION_FIELDS),
+        Chip::Esp32S3 => (ESP32S3_BLOCKS, S2_LAYOUT_FIELDS, ESP32C3_REVISION_FIELDS),
+        Chip::Esp32C3 => (ESP32C3_BLOCKS, S2_LAYOUT_FIELDS, ESP32C3_REVISION_FIELDS),
+        Chip::Esp32C2 => (ESP32C2_BLOCKS, ESP32C2_FIELDS, &[]),
+        Chip::Esp32C6 => (ESP32C6_BLOCKS, S2_LAYOUT_FIELDS, ESP32C6_REVISION_FIELDS),
+        Chip::Esp32H2 => (ESP32H2_BLOCKS, S2_LAYOUT_FIELDS, ESP32H2_REVISION_FIELDS),
+        Chip::Esp32P4 => (ESP32P4_BLOCKS, S2_LAYOUT_FIELDS, ESP32P4_REVISION_FIELDS),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EfuseValue {
+    Bool(bool),
+    Uint(u32),
+    KeyPurpose(u8),
+    Mac([u8; 6]),
+    Bytes(Vec<u8>),
+}
+
+impl fmt::Display for EfuseValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EfuseValue::Bool(value) => write!(f, "{value}"),
+            EfuseValue::Uint(value) => write!(f, "{value} (0x{value:X})"),
+            EfuseValue::KeyPurpose(value) => write!(f, "{} ({value})", key_purpose_name(*value)),
+            EfuseValue::Mac(mac) => f.write_str(&mac_string(mac)),
+            EfuseValue::Bytes(data) => {
+                for b in data {
+                    write!(f, "{b:02X}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn mac_string(mac: &[u8; 6]) -> String {
+    let mut result = String::with_capacity(17);
+    for (idx, b) in mac.iter().enumerate() {
+        if idx != 0 {
+            result.push(':');
+        }
+        write!(result, "{b:02x}").unwrap();
+    }
+    result
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EfuseField {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub value: EfuseValue,
+}
+
+/// The decoded eFuses of a chip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Efuses {
+    pub chip: Chip,
+    pub fields: Vec<EfuseField>,
+}
+
+impl Efuses {
+    /// Read the eFuse blocks for `chip` one word at a time using `read_word`
+    /// and decode the fields.
+    pub fn read<F>(chip: Chip, mut read_word: F) -> Result<Self>
+    where
+        F: FnMut(u32) -> Result<u32>,
+    {
+        let (blocks, fields, revision_fields) = tables(chip);
+        let mut data: Vec<Vec<u32>> = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            let words = (0..block.words)
+                .map(|idx| read_word(block.address + 4 * idx as u32))
+                .collect::<Result<_>>()?;
+            data.push(words);
+        }
+        let fields = fields
+            .iter()
+            .chain(revision_fields)
+            .map(|field| EfuseField {
+                name: field.name,
+                description: field.description,
+                value: decode(field, &data[field.block]),
+            })
+            .collect();
+        Ok(Efuses { chip, fields })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EfuseValue> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .map(|field| &field.value)
+    }
+
+    fn uint(&self, name: &str) -> Option<u32> {
+        match self.get(name)? {
+            EfuseValue::Uint(value) => Some(*value),
+            EfuseValue::Bool(value) => Some(*value as u32),
+            _ => None,
+        }
+    }
+
+    pub fn mac(&self) -> Option<[u8; 6]> {
+        match self.get("MAC")? {
+            EfuseValue::Mac(mac) => Some(*mac),
+            _ => None,
+        }
+    }
+
+    pub fn package_version(&self) -> Option<u32> {
+        match self.uint("PKG_VERSION") {
+            Some(version) => Some(version),
+            None => Some(self.uint("CHIP_PACKAGE")? | (self.uint("CHIP_PACKAGE_4BIT")? << 3)),
+        }
+    }
+
+    /// The major and minor chip revision.
+    pub fn chip_revision(&self) -> Option<(u32, u32)> {
+        if self.chip == Chip::Esp32 {
+            // Revision 3 also sets a bit in APB_CTRL_DATE which is not an
+            // eFuse so revisions 2 and 3 are both reported as 2.
+            let major = match (self.uint("CHIP_VER_REV1")?, self.uint("CHIP_VER_REV2")?) {
+                (0, _) => 0,
+                (_, 0) => 1,
+                _ => 2,
+            };
+            return Some((major, self.uint("WAFER_VERSION_MINOR")?));
+        }
+        let major = self.uint("WAFER_VERSION_MAJOR")?;
+        let minor = match self.uint("WAFER_VERSION_MINOR") {
+            Some(minor) => minor,
+            None => {
+                (self.uint("WAFER_VERSION_MINOR_HI")? << 3) | self.uint("WAFER_VERSION_MINOR_LO")?
+            }
+        };
+        Some((major, minor))
+    }
+
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct JsonField<'a> {
+            value: serde_json::Value,
+            description: &'a str,
+        }
+
+        struct JsonFields<'a>(&'a [EfuseField]);
+
+        impl Serialize for JsonFields<'_> {
+            fn serialize<S: Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.collect_map(self.0.iter().map(|field| {
+                    let value = match &field.value {
+                        EfuseValue::Bool(value) => (*value).into(),
+                        EfuseValue::Uint(value) => (*value).into(),
+                        EfuseValue::KeyPurpose(value) => (*value).into(),
+                        EfuseValue::Mac(mac) => mac_string(mac).into(),
+                        EfuseValue::Bytes(_) => field.value.to_string().into(),
+                    };
+                    let description = field.description;
+                    (field.name, JsonField { value, description })
+                }))
+            }
+        }
+
+        #[derive(Serialize)]
+        struct JsonEfuses<'a> {
+            chip: String,
+            fields: JsonFields<'a>,
+        }
+
+        let json = JsonEfuses {
+            chip: self.chip.to_string(),
+            fields: JsonFields(&self.fields),
+        };
+        let mut json = serde_json::to_string_pretty(&json).unwrap();
+        json.push('\n');
+        json
+    }
+}
+
+impl fmt::Display for Efuses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} eFuses:", self.chip)?;
+        let width = self
+            .fields
+            .iter()
+            .map(|field| field.name.len())
+            .max()
+            .unwrap_or(0);
+        for field in &self.fields {
+            writeln!(
+                f,
+                "{:width$}  {:50}  {}",
+                field.name, field.description, field.value
+            )?;
+        }
+        if let Some((major, minor)) = self.chip_revision() {
+            writeln!(f, "Chip revision: v{major}.{minor}")?;
+        }
+        Ok(())
+    }
+}
+
+fn decode(field: &Field, words: &[u32]) -> EfuseValue {
+    let bits = |word: usize, bit: u32, width: u32| -> u32 {
+        let value = words[word] >> bit;
+        if width >= 32 {
+            value
+        } else {
+            value & ((1 << width) - 1)
+        }
+    };
+    match field.kind {
+        Kind::Bool => EfuseValue::Bool(bits(field.word, field.bit, 1) != 0),
+        Kind::Uint => EfuseValue::Uint(bits(field.word, field.bit, field.width)),
+        Kind::KeyPurpose => EfuseValue::KeyPurpose(bits(field.word, field.bit, field.width) as u8),
+        Kind::Mac => {
+            let low = words[field.word].to_be_bytes();
+            let high = words[field.word + 1].to_be_bytes();
+            EfuseValue::Mac([high[2], high[3], low[0], low[1], low[2], low[3]])
+        }
+        Kind::Esp8266Mac => {
+            // https://github.com/espressif/esptool/blob/master/esptool/targets/esp8266.py
+            let oui = if words[3] != 0 {
+                let oui = words[3].to_be_bytes();
+                [oui[1], oui[2], oui[3]]
+            } else if bits(1, 16, 8) == 0 {
+                [0x18, 0xFE, 0x34]
+            } else {
+                [0xAC, 0xD0, 0x74]
+            };
+            let word1 = words[1].to_be_bytes();
+            let word0 = words[0].to_be_bytes();
+            EfuseValue::Mac([oui[0], oui[1], oui[2], word1[2], word1[3], word0[0]])
+        }
+        Kind::Bytes => {
+            let len = field.width as usize / 32;
+            EfuseValue::Bytes(
+                words[field.word..field.word + len]
+                    .iter()
+                    .flat_map(|word| word.to_le_bytes())
+                    .collect(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::SimulatedDevice;
+    use crate::Flasher;
+
+    #[test]
+    fn test_read_efuses() -> Result<()> {
+        let device = SimulatedDevice::new(Chip::Esp32C3);
+        // MAC 7c:df:a1:01:02:03, revision v0.4, XTS_AES_128_KEY in BLOCK_KEY1.
+        device.write_memory(0x6000_8844, 0xA101_0203);
+        device.write_memory(0x6000_8848, 0x0000_7CDF);
+        device.write_memory(0x6000_8850, 4 << 18);
+        device.write_memory(0x6000_8834, 4 << 28);
+        device.write_memory(0x6000_887C, 0x1234_5678);
+
+        let mut flasher = Flasher::with_transport(device);
+        let efuses = flasher.read_efuses()?;
+        assert_eq!(efuses.mac(), Some([0x7C, 0xDF, 0xA1, 0x01, 0x02, 0x03]));
+        assert_eq!(efuses.chip_revision(), Some((0, 4)));
+        assert_eq!(
+            efuses.get("KEY_PURPOSE_1"),
+            Some(&EfuseValue::KeyPurpose(4))
+        );
+        assert_eq!(efuses.get("SECURE_BOOT_EN"), Some(&EfuseValue::Bool(false)));
+        match efuses.get("BLOCK_USR_DATA") {
+            Some(EfuseValue::Bytes(data)) => assert_eq!(data[..4], [0x78, 0x56, 0x34, 0x12]),
+            value => panic!("Unexpected user data {value:?}"),
+        }
+        let json: serde_json::Value = serde_json::from_str(&efuses.to_json()).unwrap();
+        assert_eq!(json["chip"], "ESP32-C3");
+        assert_eq!(json["fields"]["MAC"]["value"], "7c:df:a1:01:02:03");
+        assert_eq!(json["fields"]["KEY_PURPOSE_1"]["value"], 4);
+        assert_eq!(json["fields"]["SECURE_BOOT_EN"]["value"], false);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_escaping() {
+        let efuses = Efuses {
+            chip: Chip::Esp32,
+            fields: vec![EfuseField {
+                name: "QUOTE\"D",
+                description: "A \\ backslash and \"quotes\"",
+                value: EfuseValue::Uint(7),
+            }],
+        };
+        let json: serde_json::Value = serde_json::from_str(&efuses.to_json()).unwrap();
+        assert_eq!(
+            json["fields"]["QUOTE\"D"]["description"],
+            "A \\ backslash and \"quotes\""
+        );
+        assert_eq!(json["fields"]["QUOTE\"D"]["value"], 7);
+    }
+
+    #[test]
+    fn test_esp32h2_revision() -> Result<()> {
+        // Revision v1.2 with the neighbouring bits of BLOCK1 word 3 set.
+        let efuses = Efuses::read(Chip::Esp32H2, |address| {
+            Ok(match address {
+                0x600B_0850 => (1 << 21) | (2 << 18) | 0xFF80_FFFF,
+                _ => 0,
+            })
+        })?;
+        assert_eq!(efuses.chip_revision(), Some((1, 2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_esp8266_mac() -> Result<()> {
+        let words = [0x5600_0000, 0x0001_3412, 0, 0];
+        let efuses = Efuses::read(Chip::Esp8266, |address| {
+            Ok(words[(address - 0x3FF0_0050) as usize / 4])
+        })?;
+        assert_eq!(efuses.mac(), Some([0xAC, 0xD0, 0x74, 0x34, 0x12, 0x56]));
+        Ok(())
+    }
+}
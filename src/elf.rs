@@ -14,14 +14,29 @@
 
 #![allow(dead_code)]
 
+use std::collections::HashMap;
+use std::fmt;
+
 use crate::{Error, Result};
 use binrw::{binread, BinRead};
+use gimli::{AttributeValue, DebugLine, DebugLineOffset, DebugLineStr, DebugStr, LittleEndian};
 
 use crate::chip::Chip;
 use crate::image::{EspImage, EspImageSegment};
 
-const EM_XTENSA: u16 = 94;
-const PT_LOAD: u32 = 1;
+pub(crate) const ET_EXEC: u16 = 2;
+pub(crate) const ET_CORE: u16 = 4;
+pub(crate) const EM_XTENSA: u16 = 94;
+pub(crate) const EM_RISCV: u16 = 243;
+pub(crate) const PT_LOAD: u32 = 1;
+pub(crate) const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHT_INIT_ARRAY: u32 = 14;
+const SHT_FINI_ARRAY: u32 = 15;
+const STT_FUNC: u8 = 2;
 
 // Only supports 32-bit, little-endian ELF files.
 #[binread]
@@ -36,7 +51,7 @@ struct ElfHeader {
     ei_version: u8,
 
     // Remainder of the fields.
-    #[br(temp, pad_before = 9, assert(e_type == 2), err_context("Not an executable ELF file"))]
+    #[br(pad_before = 9, assert(e_type == ET_EXEC || e_type == ET_CORE), err_context("Not an executable or core ELF file"))]
     e_type: u16,
 
     e_machine: u16,
@@ -70,13 +85,318 @@ struct ElfProgramHeader {
     p_align: u32,
 }
 
-pub fn elf_to_image(chip: Chip, data: &[u8]) -> Result<EspImage> {
+#[binread]
+#[br(little)]
+struct ElfSectionHeader {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: u32,
+    sh_addr: u32,
+    sh_offset: u32,
+    sh_size: u32,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: u32,
+    sh_entsize: u32,
+}
+
+#[binread]
+#[br(little)]
+struct ElfSymbol {
+    st_name: u32,
+    st_value: u32,
+    st_size: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+}
+
+/// A section of an ELF file.
+pub(crate) struct ElfSection<'a> {
+    pub name: &'a str,
+    pub sh_type: u32,
+    pub addr: u32,
+    pub link: u32,
+    /// The contents of the section, which are empty for `SHT_NOBITS`
+    /// sections like `.bss`.
+    pub data: &'a [u8],
+}
+
+// The NUL-terminated string at `offset` in a string table.
+fn c_str(table: &[u8], offset: u32) -> Result<&str> {
+    let invalid = || Error::FormatError("Invalid ELF string table offset".into());
+    let bytes = table.get(offset as usize..).ok_or_else(invalid)?;
+    let end = bytes.iter().position(|&b| b == 0).ok_or_else(invalid)?;
+    std::str::from_utf8(&bytes[..end]).map_err(|_| Error::FormatError("Invalid ELF string".into()))
+}
+
+/// Returns the sections of the ELF file.
+pub(crate) fn read_sections(data: &[u8]) -> Result<Vec<ElfSection<'_>>> {
     let mut cursor = std::io::Cursor::new(data);
     let elf_header = ElfHeader::read(&mut cursor)?;
-    let mut image: EspImage = Default::default();
 
-    image.header.chip_id = chip.image_chip_id();
-    image.header.entry_addr = elf_header.e_entry;
+    let sheader_offset = elf_header.e_shoff as usize;
+    let sheader_end = sheader_offset + 40 * elf_header.e_shnum as usize;
+    if sheader_end > data.len() {
+        return Err(Error::FormatError(
+            "Invalid ELF section header table".into(),
+        ));
+    }
+    let mut cursor = std::io::Cursor::new(&data[sheader_offset..sheader_end]);
+    let mut headers = Vec::with_capacity(elf_header.e_shnum as usize);
+    for _ in 0..elf_header.e_shnum {
+        headers.push(ElfSectionHeader::read(&mut cursor)?);
+    }
+
+    let contents = |header: &ElfSectionHeader| -> Result<&[u8]> {
+        if header.sh_type == SHT_NOBITS {
+            return Ok(&[]);
+        }
+        let start = header.sh_offset as usize;
+        data.get(start..start + header.sh_size as usize)
+            .ok_or_else(|| Error::FormatError("Invalid section header".into()))
+    };
+    let names = match headers.get(elf_header.e_shstrndx as usize) {
+        Some(header) => contents(header)?,
+        None => &[],
+    };
+    headers
+        .iter()
+        .map(|header| {
+            Ok(ElfSection {
+                name: if names.is_empty() {
+                    ""
+                } else {
+                    c_str(names, header.sh_name)?
+                },
+                sh_type: header.sh_type,
+                addr: header.sh_addr,
+                link: header.sh_link,
+                data: contents(header)?,
+            })
+        })
+        .collect()
+}
+
+struct Function {
+    addr: u32,
+    size: u32,
+    name: String,
+}
+
+struct LineRow {
+    addr: u32,
+    file: usize,
+    line: u32,
+    end_sequence: bool,
+}
+
+/// The function, source file, and line number containing an address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation<'a> {
+    pub function: Option<&'a str>,
+    pub file: Option<&'a str>,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.function.unwrap_or("??"))?;
+        if let Some(file) = self.file {
+            write!(f, " at {file}")?;
+            if let Some(line) = self.line {
+                write!(f, ":{line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The function symbols from `.symtab` and the line number information from
+/// `.debug_line` of an ELF file, used to map code addresses back to the
+/// source.
+pub struct ElfDebugInfo {
+    // Sorted by address.
+    functions: Vec<Function>,
+    // Sorted by address with the end of a sequence before the start of
+    // another one at the same address.
+    rows: Vec<LineRow>,
+    files: Vec<String>,
+}
+
+impl ElfDebugInfo {
+    pub fn new(data: &[u8]) -> Result<Self> {
+        let sections = read_sections(data)?;
+        let section_data = |name: &str| {
+            sections
+                .iter()
+                .find(|section| section.name == name)
+                .map_or(&[][..], |section| section.data)
+        };
+
+        let mut functions = Vec::new();
+        for symtab in sections.iter().filter(|s| s.sh_type == SHT_SYMTAB) {
+            let strtab = sections
+                .get(symtab.link as usize)
+                .ok_or_else(|| Error::FormatError("Invalid symbol string table".into()))?;
+            let mut cursor = std::io::Cursor::new(symtab.data);
+            for _ in 0..symtab.data.len() / 16 {
+                let symbol = ElfSymbol::read(&mut cursor)?;
+                if symbol.st_info & 0xF != STT_FUNC {
+                    continue;
+                }
+                functions.push(Function {
+                    addr: symbol.st_value,
+                    size: symbol.st_size,
+                    name: c_str(strtab.data, symbol.st_name)?.to_string(),
+                });
+            }
+        }
+        functions.sort_by_key(|function| function.addr);
+
+        let mut debug_info = ElfDebugInfo {
+            functions,
+            rows: Vec::new(),
+            files: Vec::new(),
+        };
+        debug_info
+            .read_line_programs(
+                section_data(".debug_line"),
+                section_data(".debug_str"),
+                section_data(".debug_line_str"),
+            )
+            .map_err(|err| Error::FormatError(format!("Invalid DWARF line info: {err}")))?;
+        debug_info
+            .rows
+            .sort_by_key(|row| (row.addr, !row.end_sequence));
+        Ok(debug_info)
+    }
+
+    fn read_line_programs(
+        &mut self,
+        debug_line: &[u8],
+        debug_str: &[u8],
+        debug_line_str: &[u8],
+    ) -> gimli::Result<()> {
+        let debug_str = DebugStr::new(debug_str, LittleEndian);
+        let debug_line_str = DebugLineStr::new(debug_line_str, LittleEndian);
+        let string = |value: AttributeValue<_>| -> gimli::Result<String> {
+            let bytes = match value {
+                AttributeValue::String(bytes) => bytes,
+                AttributeValue::DebugStrRef(offset) => debug_str.get_str(offset)?,
+                AttributeValue::DebugLineStrRef(offset) => debug_line_str.get_str(offset)?,
+                _ => return Ok(String::new()),
+            };
+            Ok(bytes.to_string_lossy().into_owned())
+        };
+
+        // Map (program offset, file index) to an index into self.files.
+        let mut file_indices: HashMap<(usize, u64), usize> = HashMap::new();
+        let len = debug_line.len();
+        let debug_line = DebugLine::new(debug_line, LittleEndian);
+        let mut offset = 0;
+        while offset < len {
+            let program = debug_line.program(DebugLineOffset(offset), 4, None, None)?;
+            let program_offset = offset;
+            let header = program.header();
+            offset += header.unit_length() + header.format().initial_length_size() as usize;
+
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                if row.end_sequence() {
+                    self.rows.push(LineRow {
+                        addr: row.address() as u32,
+                        file: usize::MAX,
+                        line: 0,
+                        end_sequence: true,
+                    });
+                    continue;
+                }
+                let key = (program_offset, row.file_index());
+                let file = match file_indices.get(&key) {
+                    Some(&file) => file,
+                    None => {
+                        let path = match row.file(header) {
+                            Some(entry) => {
+                                let name = string(entry.path_name())?;
+                                let dir = match entry.directory(header) {
+                                    Some(dir) => string(dir)?,
+                                    None => String::new(),
+                                };
+                                if dir.is_empty() || name.starts_with('/') {
+                                    name
+                                } else {
+                                    format!("{dir}/{name}")
+                                }
+                            }
+                            None => String::new(),
+                        };
+                        self.files.push(path);
+                        file_indices.insert(key, self.files.len() - 1);
+                        self.files.len() - 1
+                    }
+                };
+                self.rows.push(LineRow {
+                    addr: row.address() as u32,
+                    file,
+                    line: row.line().map_or(0, |line| line.get() as u32),
+                    end_sequence: false,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn function(&self, addr: u32) -> Option<&Function> {
+        let idx = self
+            .functions
+            .partition_point(|function| function.addr <= addr);
+        let function = self.functions.get(idx.checked_sub(1)?)?;
+        (addr - function.addr < function.size.max(1)).then(|| function)
+    }
+
+    fn line_row(&self, addr: u32) -> Option<&LineRow> {
+        let idx = self.rows.partition_point(|row| row.addr <= addr);
+        let row = self.rows.get(idx.checked_sub(1)?)?;
+        (!row.end_sequence).then(|| row)
+    }
+
+    /// Find the function and source line containing `addr`. Returns `None`
+    /// if neither is known.
+    pub fn lookup(&self, addr: u32) -> Option<SourceLocation<'_>> {
+        let function = self.function(addr);
+        let row = self.line_row(addr);
+        if function.is_none() && row.is_none() {
+            return None;
+        }
+        let file = row
+            .map(|row| self.files[row.file].as_str())
+            .filter(|file| !file.is_empty());
+        Some(SourceLocation {
+            function: function.map(|function| function.name.as_str()),
+            file,
+            line: row.map(|row| row.line).filter(|&line| line != 0),
+        })
+    }
+}
+
+/// A segment of an ELF file.
+pub(crate) struct ElfSegment<'a> {
+    pub p_type: u32,
+    pub addr: u32,
+    pub flags: u32,
+    pub data: &'a [u8],
+}
+
+impl ElfSegment<'_> {
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+fn read_segments(data: &[u8]) -> Result<(ElfHeader, Vec<ElfSegment<'_>>)> {
+    let mut cursor = std::io::Cursor::new(data);
+    let elf_header = ElfHeader::read(&mut cursor)?;
 
     let pheader_size = 32 * elf_header.e_phnum as usize;
     let pheader_offset = elf_header.e_phoff as usize;
@@ -87,27 +407,493 @@ pub fn elf_to_image(chip: Chip, data: &[u8]) -> Result<EspImage> {
             "Invalid ELF program header table".into(),
         ));
     }
+    let mut segments = Vec::new();
     let mut cursor = std::io::Cursor::new(&data[pheader_offset..pheader_end]);
     for _ in 0..elf_header.e_phnum {
         let pheader = ElfProgramHeader::read(&mut cursor)?;
-        if pheader.p_type != PT_LOAD {
-            continue;
-        }
         let start = pheader.p_offset as usize;
         let size = pheader.p_filesz as usize;
         if start + size > data.len() {
             return Err(Error::FormatError("Invalid program header".into()));
         }
-        let padded_size = (pheader.p_filesz as usize + 3) & !3;
-        let mut seg_data: Vec<u8> = Vec::with_capacity(padded_size);
-        seg_data.extend(&data[start..start + size]);
-        seg_data.resize(padded_size, 0);
-        image.segments.push(EspImageSegment {
-            load_addr: pheader.p_vaddr,
-            data: seg_data,
+        segments.push(ElfSegment {
+            p_type: pheader.p_type,
+            addr: pheader.p_vaddr,
+            flags: pheader.p_flags,
+            data: &data[start..start + size],
         });
     }
+    Ok((elf_header, segments))
+}
+
+/// Returns the entry point and the `PT_LOAD` segments of the ELF executable.
+pub(crate) fn load_segments(data: &[u8]) -> Result<(u32, Vec<ElfSegment<'_>>)> {
+    let (elf_header, mut segments) = read_segments(data)?;
+    if elf_header.e_type != ET_EXEC {
+        return Err(Error::FormatError("Not an executable ELF file".into()));
+    }
+    segments.retain(|segment| segment.p_type == PT_LOAD);
+    Ok((elf_header.e_entry, segments))
+}
+
+/// Returns the machine and all of the segments of the ELF core file.
+pub(crate) fn core_segments(data: &[u8]) -> Result<(u16, Vec<ElfSegment<'_>>)> {
+    let (elf_header, segments) = read_segments(data)?;
+    if elf_header.e_type != ET_CORE {
+        return Err(Error::FormatError("Not an ELF core file".into()));
+    }
+    Ok((elf_header.e_machine, segments))
+}
+
+// The flash MMU maps 64 kB pages.
+const IROM_ALIGN: usize = 0x10000;
+const IMAGE_HEADER_LEN: usize = 24;
+const SEGMENT_HEADER_LEN: usize = 8;
+const WP_PIN_DISABLED: u8 = 0xEE;
+
+fn padded_segment(load_addr: u32, data: &[u8]) -> EspImageSegment {
+    let padded_size = (data.len() + 3) & !3;
+    let mut seg_data: Vec<u8> = Vec::with_capacity(padded_size);
+    seg_data.extend(data);
+    seg_data.resize(padded_size, 0);
+    EspImageSegment {
+        load_addr,
+        data: seg_data,
+    }
+}
+
+fn is_flash_addr(chip: Chip, addr: u32) -> bool {
+    chip.flash_mapped_ranges()
+        .iter()
+        .any(|&(start, end)| start <= addr && addr < end)
+}
+
+// A segment of an image along with the name of the section it came from, if
+// any.
+struct NamedSegment<'a> {
+    name: &'a str,
+    segment: EspImageSegment,
+}
+
+// Like esptool, build the image from the sections with contents rather than
+// the PT_LOAD segments. Neither includes .bss or the part of a segment past
+// p_filesz, which the application zeroes.
+fn image_segments(data: &[u8]) -> Result<Vec<NamedSegment<'_>>> {
+    let segments: Vec<NamedSegment> = read_sections(data)?
+        .iter()
+        .filter(|section| {
+            matches!(
+                section.sh_type,
+                SHT_PROGBITS | SHT_INIT_ARRAY | SHT_FINI_ARRAY
+            ) && section.addr != 0
+                && !section.data.is_empty()
+        })
+        .map(|section| NamedSegment {
+            name: section.name,
+            segment: padded_segment(section.addr, section.data),
+        })
+        .collect();
+    if !segments.is_empty() {
+        return Ok(segments);
+    }
+    let (_entry, segments) = load_segments(data)?;
+    Ok(segments
+        .iter()
+        .filter(|segment| !segment.data.is_empty())
+        .map(|segment| NamedSegment {
+            name: "",
+            segment: padded_segment(segment.addr, segment.data),
+        })
+        .collect())
+}
+
+// Merge each segment that starts where the previous one ends if both are in
+// the same type of memory. The merged segment keeps the first one's name.
+fn merge_adjacent_segments(chip: Chip, segments: Vec<NamedSegment>) -> Vec<NamedSegment> {
+    let mut merged: Vec<NamedSegment> = Vec::with_capacity(segments.len());
+    for named in segments {
+        if let Some(last) = merged.last_mut() {
+            let last = &mut last.segment;
+            let segment = &named.segment;
+            let last_end = last.load_addr as usize + last.data.len();
+            if last_end == segment.load_addr as usize
+                && chip.memory_type(last.load_addr) == chip.memory_type(segment.load_addr)
+            {
+                last.data.extend(&segment.data);
+                continue;
+            }
+        }
+        merged.push(named);
+    }
+    merged
+}
+
+// The amount of padding data needed before the segment at `addr` when the
+// padding segment's header starts at file offset `pos` so that the data of
+// the segment at `addr` is congruent to `addr` modulo 64 kB.
+fn alignment_data_needed(pos: usize, addr: u32) -> usize {
+    let align_past = (addr as usize % IROM_ALIGN) as isize - SEGMENT_HEADER_LEN as isize;
+    let mut pad_len = (IROM_ALIGN - pos % IROM_ALIGN) as isize + align_past;
+    if pad_len == 0 || pad_len == IROM_ALIGN as isize {
+        return 0;
+    }
+    // The padding segment has a header as well.
+    pad_len -= SEGMENT_HEADER_LEN as isize;
+    if pad_len < 0 {
+        pad_len += IROM_ALIGN as isize;
+    }
+    pad_len as usize
+}
+
+// Order the segments the way esptool does: flash-mapped segments in address
+// order, except that the application description comes first, each at a file
+// offset congruent to its address modulo 64 kB, with the gaps filled by
+// pieces of the RAM segments or zeros, followed by the remaining RAM segments.
+fn layout_segments(chip: Chip, mut segments: Vec<NamedSegment>) -> Result<Vec<EspImageSegment>> {
+    segments.sort_by_key(|named| named.segment.load_addr);
+    let (mut flash_segments, ram_segments): (Vec<_>, Vec<_>) =
+        merge_adjacent_segments(chip, segments)
+            .into_iter()
+            .partition(|named| is_flash_addr(chip, named.segment.load_addr));
+    // When the instruction and data buses share an address range, IROM sorts
+    // ahead of DROM but the bootloader expects esp_app_desc_t in the first
+    // segment.
+    if let Some(idx) = flash_segments
+        .iter()
+        .position(|named| named.name == ".flash.appdesc")
+    {
+        let appdesc = flash_segments.remove(idx);
+        flash_segments.insert(0, appdesc);
+    }
+    let flash_segments: Vec<EspImageSegment> = flash_segments
+        .into_iter()
+        .map(|named| named.segment)
+        .collect();
+    let ram_segments = ram_segments.into_iter().map(|named| named.segment);
+    for pair in flash_segments.windows(2) {
+        if pair[0].load_addr as usize / IROM_ALIGN == pair[1].load_addr as usize / IROM_ALIGN {
+            return Err(Error::FormatError(format!(
+                "Segment loaded at 0x{:08X} lands in the same 64 kB flash mapping as the segment loaded at 0x{:08X}",
+                pair[1].load_addr, pair[0].load_addr
+            )));
+        }
+    }
+
+    let mut ram_segments: std::collections::VecDeque<_> = ram_segments.collect();
+    let mut result = Vec::new();
+    let mut pos = IMAGE_HEADER_LEN;
+    for mut segment in flash_segments {
+        loop {
+            let pad_len = alignment_data_needed(pos, segment.load_addr);
+            if pad_len == 0 {
+                break;
+            }
+            let pad_segment = match ram_segments.front_mut() {
+                Some(ram) if pad_len > SEGMENT_HEADER_LEN => {
+                    let len = pad_len.min(ram.data.len());
+                    let rest = ram.data.split_off(len);
+                    let piece = EspImageSegment {
+                        load_addr: ram.load_addr,
+                        data: std::mem::replace(&mut ram.data, rest),
+                    };
+                    ram.load_addr += len as u32;
+                    if ram.data.is_empty() {
+                        ram_segments.pop_front();
+                    }
+                    piece
+                }
+                _ => EspImageSegment {
+                    load_addr: 0,
+                    data: vec![0; pad_len],
+                },
+            };
+            pos += SEGMENT_HEADER_LEN + pad_segment.data.len();
+            result.push(pad_segment);
+        }
+        // The ESP-IDF second stage bootloader for the ESP32 does not map the
+        // last page of a segment that extends less than 0x24 bytes into it.
+        let end = pos + SEGMENT_HEADER_LEN + segment.data.len();
+        let remainder = end % IROM_ALIGN;
+        if chip == Chip::Esp32 && remainder < 0x24 {
+            segment
+                .data
+                .resize(segment.data.len() + 0x24 - remainder, 0);
+        }
+        pos += SEGMENT_HEADER_LEN + segment.data.len();
+        result.push(segment);
+    }
+    result.extend(ram_segments);
+    Ok(result)
+}
+
+/// Convert an ELF executable to an image. For chips other than the ESP8266,
+/// the segments are laid out like `esptool.py elf2image` so that the
+/// flash-mapped segments can be mapped by the bootloader.
+pub fn elf_to_image(chip: Chip, data: &[u8]) -> Result<EspImage> {
+    let (entry, segments) = load_segments(data)?;
+    let mut image: EspImage = Default::default();
+
+    image.header.chip_id = chip.image_chip_id();
+    image.header.entry_addr = entry;
+    image.header.hash_appended = 1;
+
+    if chip == Chip::Esp8266 {
+        image.segments = segments
+            .iter()
+            .map(|segment| padded_segment(segment.addr, segment.data))
+            .collect();
+    } else {
+        image.header.wp_pin = WP_PIN_DISABLED;
+        image.segments = layout_segments(chip, image_segments(data)?)?;
+    }
     image.update_metadata();
     println!("{image}");
     Ok(image)
 }
+
+/// Build an ELF file of type `e_type` for `machine` from (type, address,
+/// contents) segments and (name, type, address, link, contents) sections.
+#[cfg(test)]
+pub(crate) fn build_elf(
+    e_type: u16,
+    machine: u16,
+    segments: &[(u32, u32, Vec<u8>)],
+    sections: &[(&str, u32, u32, u32, Vec<u8>)],
+) -> Vec<u8> {
+    let mut shstrtab = vec![0u8];
+    let mut names = Vec::new();
+    for (name, ..) in sections.iter() {
+        names.push(shstrtab.len() as u32);
+        shstrtab.extend(name.as_bytes());
+        shstrtab.push(0);
+    }
+    names.push(shstrtab.len() as u32);
+    shstrtab.extend(b".shstrtab\0");
+
+    let phoff = 52;
+    let mut data = vec![0u8; phoff + 32 * segments.len()];
+    let mut pheaders = Vec::new();
+    for (p_type, addr, contents) in segments {
+        let len = contents.len() as u32;
+        for value in [*p_type, data.len() as u32, *addr, *addr, len, len, 6, 4] {
+            pheaders.extend(value.to_le_bytes());
+        }
+        data.extend(contents);
+    }
+    data[phoff..phoff + pheaders.len()].copy_from_slice(&pheaders);
+
+    let mut headers = vec![0u8; 40];
+    let contents = sections
+        .iter()
+        .map(|(_, sh_type, addr, link, contents)| (*sh_type, *addr, *link, contents))
+        .chain([(3, 0, 0, &shstrtab)]);
+    for ((sh_type, addr, link, contents), name) in contents.zip(names) {
+        let fields = [name, sh_type, 0, addr, data.len() as u32];
+        let more = [contents.len() as u32, link, 0, 1, 0];
+        for value in fields.into_iter().chain(more) {
+            headers.extend(value.to_le_bytes());
+        }
+        data.extend(contents);
+    }
+    let shoff = data.len() as u32;
+    data.extend(headers);
+
+    let mut header = b"\x7FELF\x01\x01\x01".to_vec();
+    header.resize(16, 0);
+    header.extend(e_type.to_le_bytes());
+    header.extend(machine.to_le_bytes());
+    for value in [1, 0x4008_0000, phoff as u32, shoff, 0] {
+        header.extend(u32::to_le_bytes(value));
+    }
+    let phnum = segments.len() as u16;
+    let shnum = sections.len() as u16 + 2;
+    for value in [52, 32, phnum, 40, shnum, shnum - 1] {
+        header.extend(u16::to_le_bytes(value));
+    }
+    data[..52].copy_from_slice(&header);
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_debug_info() -> Result<()> {
+        let mut symtab = vec![0u8; 16];
+        for (name, value, size, info) in
+            [(1, 0x4008_0000u32, 16u32, 0x12u8), (6, 0x3FFB_0000, 4, 1)]
+        {
+            for field in [name, value, size] {
+                symtab.extend(u32::to_le_bytes(field));
+            }
+            symtab.extend([info, 0, 1, 0]);
+        }
+        let strtab = b"\0main\0data\0".to_vec();
+
+        // A DWARF 4 line program for src/main.c with lines 10 and 12 at
+        // 0x40080000 and 0x40080008 and ending at 0x40080010.
+        let mut header = vec![4, 0, 0, 0, 0, 0, 1, 1, 1, 0xFB, 14, 13];
+        header.extend([0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+        header.extend(b"src\0\0main.c\0\x01\x00\x00\0");
+        let header_length = header.len() as u32 - 6;
+        header[2..6].copy_from_slice(&header_length.to_le_bytes());
+        let mut program = vec![0x00, 5, 0x02];
+        program.extend(0x4008_0000u32.to_le_bytes());
+        program.extend([
+            0x03, 9, 0x01, 0x02, 8, 0x03, 2, 0x01, 0x02, 8, 0x00, 1, 0x01,
+        ]);
+        let mut debug_line = ((header.len() + program.len()) as u32)
+            .to_le_bytes()
+            .to_vec();
+        debug_line.extend(header);
+        debug_line.extend(program);
+
+        let elf = build_elf(
+            ET_EXEC,
+            EM_XTENSA,
+            &[],
+            &[
+                (".text", 1, 0x4008_0000, 0, vec![0; 16]),
+                (".symtab", SHT_SYMTAB, 0, 3, symtab),
+                (".strtab", 3, 0, 0, strtab),
+                (".debug_line", 1, 0, 0, debug_line),
+            ],
+        );
+        let debug_info = ElfDebugInfo::new(&elf)?;
+        let location = debug_info.lookup(0x4008_000A).unwrap();
+        assert_eq!(location.to_string(), "main at src/main.c:12");
+        assert_eq!(debug_info.lookup(0x4008_0003).unwrap().line, Some(10));
+        // Data symbols are ignored and the line program ended.
+        assert_eq!(debug_info.lookup(0x3FFB_0000), None);
+        assert_eq!(debug_info.lookup(0x4008_0010), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_elf_to_image_layout() -> Result<()> {
+        let elf = build_elf(
+            ET_EXEC,
+            EM_XTENSA,
+            &[],
+            &[
+                (
+                    ".flash.rodata",
+                    SHT_PROGBITS,
+                    0x3F40_0020,
+                    0,
+                    vec![1; 0xFFF0],
+                ),
+                (".dram0.data", SHT_PROGBITS, 0x3FFB_0000, 0, vec![2; 0x8000]),
+                (
+                    ".dram0.data2",
+                    SHT_PROGBITS,
+                    0x3FFB_8000,
+                    0,
+                    vec![3; 0x8000],
+                ),
+                (".dram0.bss", SHT_NOBITS, 0x3FFC_0000, 0, vec![]),
+                (".iram0.text", SHT_PROGBITS, 0x4008_0000, 0, vec![4; 0x3E]),
+                (".flash.text", SHT_PROGBITS, 0x400D_0020, 0, vec![5; 0x200]),
+            ],
+        );
+        let image = elf_to_image(Chip::Esp32, &elf)?;
+        let layout: Vec<(u32, usize)> = image
+            .segments
+            .iter()
+            .map(|segment| (segment.load_addr, segment.data.len()))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                // Padded so the last page has at least 0x24 bytes.
+                (0x3F40_0020, 0x10004),
+                // Padding taken from the merged RAM segments.
+                (0x3FFB_0000, 0xFFEC),
+                (0x400D_0020, 0x200),
+                (0x3FFB_FFEC, 0x14),
+                (0x4008_0000, 0x40),
+            ]
+        );
+        let mut offset = IMAGE_HEADER_LEN;
+        for segment in &image.segments {
+            offset += SEGMENT_HEADER_LEN;
+            if is_flash_addr(Chip::Esp32, segment.load_addr) {
+                assert_eq!(offset % IROM_ALIGN, segment.load_addr as usize % IROM_ALIGN);
+            }
+            offset += segment.data.len();
+        }
+        assert_eq!(image.header.segment_count, 5);
+        assert_eq!(image.header.wp_pin, WP_PIN_DISABLED);
+
+        // Two flash segments cannot share a 64 kB page.
+        let elf = build_elf(
+            ET_EXEC,
+            EM_XTENSA,
+            &[],
+            &[
+                (".flash.rodata", SHT_PROGBITS, 0x3F40_0020, 0, vec![1; 4]),
+                (".flash.rodata2", SHT_PROGBITS, 0x3F40_1000, 0, vec![1; 4]),
+            ],
+        );
+        assert!(elf_to_image(Chip::Esp32, &elf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_elf_to_image_unified_flash_map() -> Result<()> {
+        // On the ESP32-C6, IROM at 0x42000000 sorts before DROM at
+        // 0x42800000 and the instruction and data buses share the RAM.
+        let elf = build_elf(
+            ET_EXEC,
+            EM_RISCV,
+            &[],
+            &[
+                (".iram0.text", SHT_PROGBITS, 0x4080_0000, 0, vec![1; 0x40]),
+                (".dram0.data", SHT_PROGBITS, 0x4080_0040, 0, vec![2; 0x20]),
+                (".flash.text", SHT_PROGBITS, 0x4200_0020, 0, vec![3; 0x100]),
+                (
+                    ".flash.appdesc",
+                    SHT_PROGBITS,
+                    0x4281_0020,
+                    0,
+                    vec![4; 0x100],
+                ),
+                (".flash.rodata", SHT_PROGBITS, 0x4281_0120, 0, vec![5; 0x80]),
+            ],
+        );
+        let image = elf_to_image(Chip::Esp32C6, &elf)?;
+        let layout: Vec<(u32, usize)> = image
+            .segments
+            .iter()
+            .map(|segment| (segment.load_addr, segment.data.len()))
+            .collect();
+        // The segments esptool.py elf2image writes for the same sections.
+        assert_eq!(
+            layout,
+            [
+                (0x4281_0020, 0x180),
+                (0x4080_0000, 0x60),
+                (0, 0xFE08),
+                (0x4200_0020, 0x100),
+            ]
+        );
+        assert_eq!(
+            &image.segments[0].data[0xFC..0x104],
+            [4, 4, 4, 4, 5, 5, 5, 5]
+        );
+
+        // Adjacent segments in different types of memory are not merged.
+        let elf = build_elf(
+            ET_EXEC,
+            EM_XTENSA,
+            &[],
+            &[
+                (".iram0.text", SHT_PROGBITS, 0x400B_FFF0, 0, vec![1; 0x10]),
+                (".rtc.text", SHT_PROGBITS, 0x400C_0000, 0, vec![2; 0x10]),
+            ],
+        );
+        assert_eq!(elf_to_image(Chip::Esp32, &elf)?.segments.len(), 2);
+        Ok(())
+    }
+}
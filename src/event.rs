@@ -96,6 +96,15 @@ fn format_data(f: &mut std::fmt::Formatter<'_>, data: &[u8]) -> std::fmt::Result
     Ok(())
 }
 
+/// Displays data as a hex dump in the same format used for tracing events.
+pub struct HexDump<'a>(pub &'a [u8]);
+
+impl<'a> std::fmt::Display for HexDump<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        format_data(f, self.0)
+    }
+}
+
 impl<'a> std::fmt::Display for Event<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
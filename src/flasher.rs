@@ -13,21 +13,31 @@
 // limitations under the License.
 
 use std::borrow::Cow;
-use std::cmp::{max, min};
-use std::io::{self, Cursor, Write};
+use std::cmp::min;
+use std::io::{Cursor, Write};
 use std::rc::Rc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use binrw::BinRead;
 use flate2::write::DeflateEncoder;
 use flate2::Compression;
-use serialport::SerialPort;
 
 use crate::chip::Chip;
-use crate::event::{Event, EventObserver, EventProvider};
+use crate::coredump::CoreDump;
+use crate::efuse::Efuses;
+use crate::event::EventObserver;
+use crate::image::{check_patchable, patch_flash_params, EspImage, FlashParams, FlashSize};
+use crate::partition::{
+    EspPartitionTable, PARTITION_TABLE_OFFSET, PARTITION_TABLE_SIZE, SUBTYPE_DATA_COREDUMP,
+    TYPE_DATA,
+};
 use crate::protocol::Protocol;
-use crate::stub::Stub;
+use crate::reset::ResetStrategy;
+use crate::rfc2217::Rfc2217Transport;
+use crate::security::SecurityInfo;
+use crate::stub::{self, Stub};
 use crate::timeout::ErrorExt;
+use crate::transport::{TcpTransport, Transport};
 use crate::Result;
 use crate::{from_be16, from_le, Error};
 
@@ -38,7 +48,11 @@ const FLASH_SECTOR_SIZE: usize = 0x1000; //  4 kB
 const ROM_PACKET_SIZE: usize = 0x400; //  1 kB
 const STUB_PACKET_SIZE: usize = 0x4000; // 16 kB
 const DATA_SIZE_MULTIPLE: usize = 4;
+const DIFF_BLOCK_SIZE: usize = 0x10000; // 64 kB
+const READ_FLASH_PACKET_SIZE: u32 = 0x1000; // 4 kB
+const READ_FLASH_MAX_PENDING_PACKETS: u32 = 64;
 
+const UART_CLKDIV_MASK: u32 = 0xFFFFF;
 const CHIP_MAGIC_REG: u32 = 0x40001000;
 
 #[derive(Clone, Copy, Debug, thiserror::Error)]
@@ -55,6 +69,9 @@ pub enum FlasherError {
     #[error("Flash offset not a multiple of 4096")]
     MisalignedFlashOffset,
 
+    #[error("Erase size not a multiple of 4096")]
+    MisalignedEraseSize,
+
     #[error("Stub loader already running")]
     StubAlreadyRunning,
 
@@ -63,26 +80,73 @@ pub enum FlasherError {
 
     #[error("Data does not fit in flash at the given offset")]
     DataTooLarge,
+
+    #[error("Segment at 0x{:08X} is in a flash-mapped region", .0)]
+    FlashMappedSegment(u32),
+
+    #[error("Segment at 0x{:08X} overlaps the running stub loader; use the ROM loader", .0)]
+    StubSegment(u32),
+
+    #[error("No coredump partition in the partition table")]
+    NoCoreDumpPartition,
+
+    #[error("The coredump partition is empty")]
+    NoCoreDump,
+
+    #[error("Operation requires the stub loader")]
+    StubRequired,
+
+    #[error("The ESP8266 ROM loader cannot verify flash; use the stub loader")]
+    VerifyUnsupported,
+
+    #[error("MD5 digest of data read from flash does not match")]
+    DigestMismatch,
+
+    #[error(
+        "Verification of flash at 0x{:08X} failed: expected MD5 {}, got {}",
+        .offset,
+        hex_string(.expected),
+        hex_string(.actual)
+    )]
+    VerifyFailed {
+        offset: u32,
+        expected: [u8; 16],
+        actual: [u8; 16],
+    },
 }
 
-struct TimeoutSerialPort {
-    inner: Box<dyn SerialPort>,
-    start: Instant,
-    timeout: Duration,
-    event_provider: EventProvider,
+fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
 }
 
-impl std::io::Read for TimeoutSerialPort {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let timeout = max(
-            DEFAULT_SERIAL_TIMEOUT,
-            self.timeout.saturating_sub(self.start.elapsed()),
-        );
-        self.inner.set_timeout(timeout)?;
-        let size = self.inner.read(buf)?;
-        self.event_provider
-            .send_event(Event::SerialRead(Cow::from(&buf[..size])));
-        Ok(size)
+/// Options for [`Flasher::write_flash`].
+#[derive(Clone, Copy, Debug)]
+pub struct WriteFlashOptions {
+    /// Send the data deflate compressed. Defaults to true. The ESP8266 ROM
+    /// loader does not support compressed writes so the data is sent
+    /// uncompressed.
+    pub compress: bool,
+    /// Compare the MD5 digest of the written region to the digest of the data
+    /// after writing. The ESP8266 ROM loader does not support SPI_FLASH_MD5 so
+    /// verifying with it fails before anything is written.
+    pub verify: bool,
+    /// Compare the MD5 digest of each 64 kB block of flash to the digest of
+    /// the corresponding block of data and only erase and write the blocks
+    /// that differ. The ESP8266 ROM loader does not support SPI_FLASH_MD5 so
+    /// all of the data is written.
+    pub skip_unchanged: bool,
+    /// Reboot into the application after writing.
+    pub reboot: bool,
+}
+
+impl Default for WriteFlashOptions {
+    fn default() -> Self {
+        WriteFlashOptions {
+            compress: true,
+            verify: false,
+            skip_unchanged: false,
+            reboot: false,
+        }
     }
 }
 
@@ -92,19 +156,48 @@ pub struct Flasher {
     attached: bool,
     flash_id: Option<(u8, u16)>,
     flash_size: Option<usize>,
+    crystal_frequency: Option<u32>,
+    bootloader_flash_params: FlashParams,
+    detect_bootloader_flash_size: bool,
+    // The `(start, end)` address ranges of the running stub's text and data.
+    stub_ranges: Vec<(u32, u32)>,
 }
 
 impl Flasher {
+    /// Open a connection to the device at `path`. The path is either the path
+    /// to a serial port, an `rfc2217://host:port` URL for a serial port
+    /// exposed by an RFC 2217 server, or a `socket://host:port` URL for a raw
+    /// TCP connection.
     pub fn new(path: &str) -> Result<Self> {
+        if let Some(addr) = path.strip_prefix("rfc2217://") {
+            // Ignore any options such as those pyserial accepts.
+            let addr = addr.split('?').next().unwrap();
+            return Ok(Self::with_transport(Rfc2217Transport::connect(addr)?));
+        }
+        if let Some(addr) = path.strip_prefix("socket://") {
+            return Ok(Self::with_transport(TcpTransport::connect(addr)?));
+        }
+        // The native port on Unix can set DTR and RTS at the same time.
+        #[cfg(unix)]
+        let serial = serialport::new(path, 115200).open_native()?;
+        #[cfg(not(unix))]
         let serial = serialport::new(path, 115200).open()?;
+        Ok(Self::with_transport(serial))
+    }
 
-        Ok(Flasher {
-            protocol: Protocol::new(serial),
+    /// Create a flasher which communicates with the device via `transport`.
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Self {
+        Flasher {
+            protocol: Protocol::new(Box::new(transport)),
             chip: None,
             attached: false,
             flash_id: None,
             flash_size: None,
-        })
+            crystal_frequency: None,
+            bootloader_flash_params: Default::default(),
+            detect_bootloader_flash_size: false,
+            stub_ranges: Vec::new(),
+        }
     }
 
     pub fn add_observer<O>(&mut self, observer: O)
@@ -125,12 +218,125 @@ impl Flasher {
         self.chip = None;
         self.attached = false;
         self.flash_id = None;
+        self.crystal_frequency = None;
 
         self.protocol.connect()?;
-        let magic = self.protocol.read_reg(CHIP_MAGIC_REG)?;
-        self.chip = Chip::try_from_magic(magic);
-        self.chip
-            .ok_or_else(|| FlasherError::UnknownDevice(magic).into())
+        // The ESP32-C3 and later report their chip ID in the security info.
+        // Older chips either don't support the command or don't report the
+        // chip ID so fall back to the magic value.
+        self.chip = match self.protocol.get_security_info() {
+            Ok(info) => info.chip(),
+            Err(Error::CommandError(_)) => None,
+            Err(err) if err.is_timeout() => None,
+            Err(err) => return Err(err),
+        };
+        let chip = match self.chip {
+            Some(chip) => chip,
+            None => {
+                let magic = self.protocol.read_reg(CHIP_MAGIC_REG)?;
+                Chip::try_from_magic(magic).ok_or(FlasherError::UnknownDevice(magic))?
+            }
+        };
+        // Detect the crystal frequency now, while the loader is still using
+        // the baud rate it was reset into.
+        self.crystal_frequency = Some(self.detect_crystal_frequency(chip)?);
+        self.chip = Some(chip);
+        Ok(chip)
+    }
+
+    /// Read and decode the eFuses.
+    pub fn read_efuses(&mut self) -> Result<Efuses> {
+        let chip = self.ensure_connected()?;
+        let protocol = &mut self.protocol;
+        Efuses::read(chip, |address| protocol.read_reg(address))
+    }
+
+    pub fn mac_address(&mut self) -> Result<[u8; 6]> {
+        // Every eFuse table has a MAC.
+        Ok(self.read_efuses()?.mac().unwrap())
+    }
+
+    /// The major and minor chip revision, if the chip records it in eFuses.
+    pub fn chip_revision(&mut self) -> Result<Option<(u32, u32)>> {
+        Ok(self.read_efuses()?.chip_revision())
+    }
+
+    fn detect_crystal_frequency(&mut self, chip: Chip) -> Result<u32> {
+        let frequencies = chip.crystal_frequencies();
+        let (clkdiv_reg, divider) = match chip.uart_clkdiv_reg() {
+            Some(reg) => reg,
+            None => return Ok(frequencies[0]),
+        };
+        // The UART clock is derived from the crystal so the divider for the
+        // current baud rate gives an estimate of the crystal frequency.
+        let clkdiv = self.protocol.read_reg(clkdiv_reg)? & UART_CLKDIV_MASK;
+        let baud_rate = self.protocol.baud_rate()?;
+        let estimate = (baud_rate as u64 * clkdiv as u64 / 1_000_000 / divider as u64) as u32;
+        Ok(*frequencies
+            .iter()
+            .min_by_key(|&&freq| freq.abs_diff(estimate))
+            .unwrap())
+    }
+
+    /// The crystal frequency in MHz, detected when connecting.
+    pub fn crystal_frequency(&mut self) -> Result<u32> {
+        self.ensure_connected()?;
+        Ok(self.crystal_frequency.unwrap())
+    }
+
+    /// The baud rate the chip will actually use when asked to change to
+    /// `baud_rate`. The UART clock of the ESP8266, ESP32, and ESP32-C2 comes
+    /// from the crystal and the UART can only divide it by an integer (plus a
+    /// 4-bit fraction on the ESP32 and ESP32-C2) so some rates cannot be
+    /// achieved. Other chips are assumed to achieve any rate.
+    pub fn achievable_baud_rate(&mut self, baud_rate: u32) -> Result<u32> {
+        let chip = self.ensure_connected()?;
+        let crystal = self.crystal_frequency.unwrap() as u64 * 1_000_000;
+        let (uart_clock, fraction_bits) = match chip.uart_clkdiv_reg() {
+            Some((_, divider)) if chip == Chip::Esp8266 => (crystal * divider as u64, 0),
+            Some((_, divider)) => (crystal * divider as u64, 4),
+            None => return Ok(baud_rate),
+        };
+        let scaled_clock = uart_clock << fraction_bits;
+        // Round to the nearest divider but never divide by less than one.
+        let clkdiv = ((scaled_clock + baud_rate as u64 / 2) / baud_rate.max(1) as u64)
+            .clamp(1 << fraction_bits, UART_CLKDIV_MASK as u64);
+        Ok((scaled_clock / clkdiv) as u32)
+    }
+
+    /// Read the security info. This is supported by the ESP32-S2 and later.
+    pub fn security_info(&mut self) -> Result<SecurityInfo> {
+        self.ensure_connected()?;
+        self.protocol.get_security_info()
+    }
+
+    /// Read the 32-bit word at `address`.
+    pub fn read_mem(&mut self, address: u32) -> Result<u32> {
+        self.ensure_connected()?;
+        self.protocol.read_reg(address)
+    }
+
+    /// Write the bits of `value` selected by `mask` to the 32-bit word at
+    /// `address` and then wait `delay` microseconds.
+    pub fn write_mem(&mut self, address: u32, value: u32, mask: u32, delay: u32) -> Result<()> {
+        self.ensure_connected()?;
+        self.protocol.write_reg_masked(address, value, mask, delay)
+    }
+
+    /// Read `len` bytes of memory starting at `address` one word at a time.
+    pub fn dump_mem(&mut self, address: u32, len: u32) -> Result<Vec<u8>> {
+        self.ensure_connected()?;
+        let start = address & !3;
+        let skip = (address - start) as usize;
+        let mut data = Vec::with_capacity(len as usize + 8);
+        let mut word_addr = start;
+        while data.len() < skip + len as usize {
+            data.extend(self.protocol.read_reg(word_addr)?.to_le_bytes());
+            word_addr = word_addr.wrapping_add(4);
+        }
+        data.drain(..skip);
+        data.truncate(len as usize);
+        Ok(data)
     }
 
     fn ensure_connected(&mut self) -> Result<Chip> {
@@ -307,12 +513,39 @@ impl Flasher {
         Ok(())
     }
 
+    /// Set how the device is reset when connecting and after finishing.
+    pub fn set_reset_strategy(&mut self, reset_strategy: ResetStrategy) {
+        self.protocol.set_reset_strategy(reset_strategy);
+    }
+
     pub fn reset(&mut self, enter_bootloader: bool) -> Result<()> {
         self.attached = false;
         self.chip = None;
         self.protocol.reset(enter_bootloader)
     }
 
+    /// Read a line of output, e.g., from the application after a reset.
+    pub fn read_line(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        self.protocol.read_line(timeout)
+    }
+
+    /// The part of a line read so far by `read_line` calls that timed out,
+    /// e.g., a prompt.
+    pub fn partial_line(&self) -> &[u8] {
+        self.protocol.partial_line()
+    }
+
+    /// Write `data` to the device without any framing.
+    pub fn write_raw(&mut self, data: &[u8]) -> Result<()> {
+        self.protocol.write_raw(data)
+    }
+
+    /// Set the speed of the serial port without telling the device. Use
+    /// `change_baud_rate` to change the speed used by the loader.
+    pub fn set_port_baud_rate(&mut self, baud_rate: u32) -> Result<()> {
+        self.protocol.set_baud_rate(baud_rate)
+    }
+
     fn write_all_data(
         &mut self,
         data: &[u8],
@@ -344,58 +577,271 @@ impl Flasher {
             .mem_begin(total_size, num_packets, packet_size as u32, addr)?;
         self.write_all_data(data, packet_size, true, Protocol::mem_data)?;
 
-        if let Some(entry) = entry {
-            // The ROM loader may start executing the code before the
-            // transmit fifo is empty, so ignore timeouts.
-            let ret = self.protocol.mem_end(true, entry);
+        match entry {
+            Some(entry) => self.execute(entry),
+            None => Ok(()),
+        }
+    }
+
+    // Jump to `entry` after writing to RAM.
+    fn execute(&mut self, entry: u32) -> Result<()> {
+        // The ROM loader may start executing the code before the
+        // transmit fifo is empty, so ignore timeouts.
+        let ret = self.protocol.mem_end(true, entry);
 
-            if !self.protocol.is_rom_loader() || !ret.is_timeout() {
-                return ret;
-            }
+        if !self.protocol.is_rom_loader() || !ret.is_timeout() {
+            return ret;
         }
         Ok(())
     }
 
+    /// Write the segments of `image` to RAM and jump to its entry address.
+    /// Nothing is written if any segment would be loaded into a region
+    /// mapped to flash or would overwrite the running stub loader.
+    pub fn load_ram(&mut self, image: &EspImage) -> Result<()> {
+        let chip = self.ensure_connected()?;
+        let stub_ranges: &[(u32, u32)] = if self.protocol.is_rom_loader() {
+            &[]
+        } else {
+            &self.stub_ranges
+        };
+        // Padding segments are loaded at address 0 and are skipped.
+        let segments = image
+            .segments
+            .iter()
+            .filter(|seg| seg.load_addr != 0 && !seg.data.is_empty());
+        for segment in segments.clone() {
+            let start = segment.load_addr;
+            let end = start.saturating_add(segment.data.len() as u32);
+            if chip
+                .flash_mapped_ranges()
+                .iter()
+                .any(|&(range_start, range_end)| start < range_end && range_start < end)
+            {
+                return Err(FlasherError::FlashMappedSegment(start).into());
+            }
+            if stub_ranges
+                .iter()
+                .any(|&(range_start, range_end)| start < range_end && range_start < end)
+            {
+                return Err(FlasherError::StubSegment(start).into());
+            }
+        }
+        for segment in segments {
+            self.write_ram(segment.load_addr, &segment.data, None)?;
+        }
+        self.execute(image.header.entry_addr)
+    }
+
+    // The size to pass to FLASH_BEGIN to erase `size` bytes at `flash_offset`.
+    fn erase_size(&self, chip: Chip, flash_offset: u32, size: usize) -> u32 {
+        if chip == Chip::Esp8266 && self.protocol.is_rom_loader() {
+            esp8266_rom_erase_size(flash_offset, size)
+        } else {
+            size as u32
+        }
+    }
+
+    /// Erase the entire flash. The ROM loader doesn't support erasing so it
+    /// erases via a FLASH_BEGIN command for the whole flash with no packets.
+    pub fn erase_flash(&mut self) -> Result<()> {
+        let chip = self.ensure_connected()?;
+        self.ensure_attached()?;
+        if self.protocol.is_rom_loader() {
+            let size = self.flash_size()?;
+            let erase_size = self.erase_size(chip, 0, size);
+            self.protocol
+                .flash_begin(erase_size, 0, ROM_PACKET_SIZE as u32, 0)
+        } else {
+            self.protocol.erase_flash()
+        }
+    }
+
+    /// Erase `size` bytes of flash starting at `flash_offset`. Both must be
+    /// multiples of the flash sector size.
+    pub fn erase_region(&mut self, flash_offset: u32, size: u32) -> Result<()> {
+        if flash_offset as usize & (FLASH_SECTOR_SIZE - 1) != 0 {
+            return Err(FlasherError::MisalignedFlashOffset.into());
+        }
+        if size as usize & (FLASH_SECTOR_SIZE - 1) != 0 {
+            return Err(FlasherError::MisalignedEraseSize.into());
+        }
+        let chip = self.ensure_connected()?;
+        self.ensure_attached()?;
+        if flash_offset as usize + size as usize > self.flash_size()? {
+            return Err(FlasherError::DataTooLarge.into());
+        }
+        if self.protocol.is_rom_loader() {
+            let erase_size = self.erase_size(chip, flash_offset, size as usize);
+            self.protocol
+                .flash_begin(erase_size, 0, ROM_PACKET_SIZE as u32, flash_offset)
+        } else {
+            self.protocol.erase_region(flash_offset, size)
+        }
+    }
+
+    /// Set the flash parameters that `write_flash` writes into the header of a
+    /// bootloader image written to the chip's bootloader offset. If
+    /// `detect_size` is true, the flash size is set to the size reported by
+    /// `flash_size`, overriding `params.size`.
+    pub fn set_bootloader_flash_params(&mut self, params: FlashParams, detect_size: bool) {
+        self.bootloader_flash_params = params;
+        self.detect_bootloader_flash_size = detect_size;
+    }
+
+    // Whether `data` written at `flash_offset` is a bootloader image that
+    // should get the flash parameters set by `set_bootloader_flash_params`.
+    fn is_bootloader_to_patch(&self, chip: Chip, flash_offset: u32, data: &[u8]) -> bool {
+        flash_offset == chip.bootloader_flash_offset()
+            && data.first() == Some(&0xE9)
+            && (!self.bootloader_flash_params.is_empty() || self.detect_bootloader_flash_size)
+    }
+
+    /// Returns why `write_flash` will write `data` to `flash_offset` without
+    /// the flash parameters set by `set_bootloader_flash_params`, if `data` is
+    /// a bootloader image that `check_patchable` rejects.
+    pub fn bootloader_patch_error(
+        &mut self,
+        flash_offset: u32,
+        data: &[u8],
+    ) -> Result<Option<Error>> {
+        let chip = self.ensure_connected()?;
+        if !self.is_bootloader_to_patch(chip, flash_offset, data) {
+            return Ok(None);
+        }
+        Ok(check_patchable(chip, data).err())
+    }
+
+    /// Write `data` to flash at `flash_offset` as controlled by `options`.
+    ///
+    /// If `data` is an image written to the chip's bootloader offset, the flash
+    /// parameters set by `set_bootloader_flash_params` are written into its
+    /// header and its checksum and hash are updated. Data that
+    /// `check_patchable` rejects, such as a signed or encrypted bootloader, is
+    /// written unmodified.
+    ///
+    /// Returns the number of bytes that were skipped because they were unchanged.
     pub fn write_flash(
         &mut self,
         flash_offset: u32,
         data: &[u8],
-        compress: bool,
-        reboot: bool,
-    ) -> Result<()> {
+        options: WriteFlashOptions,
+    ) -> Result<usize> {
+        let WriteFlashOptions {
+            compress,
+            verify,
+            skip_unchanged,
+            reboot,
+        } = options;
         if flash_offset as usize & (FLASH_SECTOR_SIZE - 1) != 0 {
             return Err(FlasherError::MisalignedFlashOffset.into());
         }
         let chip = self.ensure_connected()?;
+        // The ESP8266 ROM loader supports neither SPI_FLASH_MD5 nor the
+        // FLASH_DEFL_* commands.
+        let esp8266_rom = chip == Chip::Esp8266 && self.protocol.is_rom_loader();
+        let supports_md5 = !esp8266_rom;
+        if verify && !supports_md5 {
+            return Err(FlasherError::VerifyUnsupported.into());
+        }
+        let compress = compress && !esp8266_rom;
 
-        let packet_size = if self.protocol.is_rom_loader() {
-            ROM_PACKET_SIZE
+        let patched;
+        let data = if self.is_bootloader_to_patch(chip, flash_offset, data)
+            && check_patchable(chip, data).is_ok()
+        {
+            let mut params = self.bootloader_flash_params;
+            if self.detect_bootloader_flash_size {
+                let size = FlashSize::from_bytes(self.flash_size()?)
+                    .ok_or(FlasherError::CannotDetectFlashSize)?;
+                params.size = Some(size);
+            }
+            patched = patch_flash_params(chip, data, &params)?;
+            &patched[..]
         } else {
-            STUB_PACKET_SIZE
+            data
         };
 
         let mask = DATA_SIZE_MULTIPLE - 1;
         let padded_size = (data.len() + mask) & !mask;
-        let padding_size = padded_size - data.len();
-        let erase_size = if chip == Chip::Esp8266 {
-            todo!("ESP8266 has some bizarre erase bug");
+        if flash_offset as usize + padded_size > self.flash_size()? {
+            return Err(FlasherError::DataTooLarge.into());
+        }
+        let data: Cow<[u8]> = if padded_size == data.len() {
+            Cow::Borrowed(data)
         } else {
-            padded_size as u32
+            let mut padded = data.to_vec();
+            padded.resize(padded_size, 0xFF);
+            Cow::Owned(padded)
         };
 
-        if flash_offset + erase_size > self.flash_size()? as u32 {
-            return Err(FlasherError::DataTooLarge.into());
+        let mut skipped = 0;
+        let mut written = true;
+        if skip_unchanged && supports_md5 {
+            // Find the runs of consecutive blocks that differ.
+            let mut runs: Vec<(usize, usize)> = Vec::new();
+            for (idx, block) in data.chunks(DIFF_BLOCK_SIZE).enumerate() {
+                let start = idx * DIFF_BLOCK_SIZE;
+                let actual = self
+                    .protocol
+                    .spi_flash_md5(flash_offset + start as u32, block.len() as u32)?;
+                if actual == md5::compute(block).0 {
+                    skipped += block.len();
+                    continue;
+                }
+                match runs.last_mut() {
+                    Some((_, end)) if *end == start => *end += block.len(),
+                    _ => runs.push((start, start + block.len())),
+                }
+            }
+            written = !runs.is_empty();
+            for (start, end) in runs {
+                self.write_flash_region(flash_offset + start as u32, &data[start..end], compress)?;
+            }
+        } else {
+            self.write_flash_region(flash_offset, &data, compress)?;
         }
 
+        if verify {
+            let expected = md5::compute(&data).0;
+            let actual = self
+                .protocol
+                .spi_flash_md5(flash_offset, padded_size as u32)?;
+            if actual != expected {
+                return Err(FlasherError::VerifyFailed {
+                    offset: flash_offset,
+                    expected,
+                    actual,
+                }
+                .into());
+            }
+        }
+
+        // The loader rejects FLASH_END and FLASH_DEFL_END without a preceding
+        // begin command so reset the chip instead if nothing was written.
+        match (reboot, written, compress) {
+            (true, true, true) => self.protocol.flash_defl_end(reboot)?,
+            (true, true, false) => self.protocol.flash_end(reboot)?,
+            (true, false, _) => self.reset(false)?,
+            (false, _, _) => (),
+        }
+        Ok(skipped)
+    }
+
+    // Erase and write `data` to flash at `flash_offset`. The length of `data`
+    // must be a multiple of `DATA_SIZE_MULTIPLE`.
+    fn write_flash_region(&mut self, flash_offset: u32, data: &[u8], compress: bool) -> Result<()> {
+        let chip = self.ensure_connected()?;
+        let packet_size = if self.protocol.is_rom_loader() {
+            ROM_PACKET_SIZE
+        } else {
+            STUB_PACKET_SIZE
+        };
+        let erase_size = self.erase_size(chip, flash_offset, data.len());
+
         if compress {
-            // Compress the data and the padding bytes.
             let mut e = DeflateEncoder::new(Vec::new(), Compression::best());
             e.write_all(data)?;
-            if padding_size > 0 {
-                let mut padding = Vec::with_capacity(padding_size);
-                padding.resize(padding_size, 0xFF);
-                e.write_all(&padding)?;
-            }
             let compressed_data: Vec<u8> = e.finish()?;
             let num_packets = ((compressed_data.len() + (packet_size - 1)) / packet_size) as u32;
 
@@ -411,20 +857,66 @@ impl Flasher {
                 packet_size,
                 false,
                 Protocol::flash_defl_data,
-            )?;
+            )
         } else {
             // Pad the final packet to packet_size.
-            let padded_size = (padded_size + packet_size - 1) & !(packet_size - 1);
+            let padded_size = (data.len() + packet_size - 1) & !(packet_size - 1);
             let num_packets = (padded_size / packet_size) as u32;
             self.protocol
                 .flash_begin(erase_size, num_packets, packet_size as u32, flash_offset)?;
-            self.write_all_data(data, packet_size, true, Protocol::flash_data)?;
+            self.write_all_data(data, packet_size, true, Protocol::flash_data)
+        }
+    }
+
+    /// Read `len` bytes of flash starting at `flash_offset`. This requires the
+    /// stub loader.
+    pub fn read_flash(&mut self, flash_offset: u32, len: u32) -> Result<Vec<u8>> {
+        self.ensure_connected()?;
+        if self.protocol.is_rom_loader() {
+            return Err(FlasherError::StubRequired.into());
+        }
+        if flash_offset as usize + len as usize > self.flash_size()? {
+            return Err(FlasherError::DataTooLarge.into());
+        }
+        let (data, digest) = self.protocol.read_flash(
+            flash_offset,
+            len,
+            READ_FLASH_PACKET_SIZE,
+            READ_FLASH_MAX_PENDING_PACKETS,
+        )?;
+        if md5::compute(&data).0 != digest {
+            return Err(FlasherError::DigestMismatch.into());
         }
+        Ok(data)
+    }
 
-        match (reboot, compress) {
-            (true, true) => self.protocol.flash_defl_end(reboot),
-            (true, false) => self.protocol.flash_end(reboot),
-            (false, _) => Ok(()),
+    /// Read the partition table from its default location.
+    pub fn read_partition_table(&mut self) -> Result<EspPartitionTable> {
+        let data = self.read_flash(PARTITION_TABLE_OFFSET, PARTITION_TABLE_SIZE)?;
+        data.as_slice().try_into()
+    }
+
+    /// Read the core dump from the coredump partition.
+    pub fn read_coredump(&mut self) -> Result<CoreDump> {
+        let table = self.read_partition_table()?;
+        let (offset, size) = table
+            .find(TYPE_DATA, SUBTYPE_DATA_COREDUMP)
+            .ok_or(FlasherError::NoCoreDumpPartition)?;
+        // Read the length first to avoid reading the whole partition.
+        let len = from_le(&self.read_flash(offset, 4)?);
+        if len < 4 || len > size {
+            return Err(FlasherError::NoCoreDump.into());
+        }
+        CoreDump::from_bytes(&self.read_flash(offset, len)?)
+    }
+
+    /// Run the stub embedded for the connected chip. Returns false if no stub
+    /// is embedded, e.g., because the `bundled-stubs` feature is disabled.
+    pub fn run_bundled_stub(&mut self) -> Result<bool> {
+        let chip = self.ensure_connected()?;
+        match stub::bundled(chip) {
+            Some(stub) => self.run_stub(stub).map(|_| true),
+            None => Ok(false),
         }
     }
 
@@ -450,6 +942,371 @@ impl Flasher {
             return Err(FlasherError::InvalidStubHello.into());
         }
         self.protocol.set_rom_loader(false);
+        self.stub_ranges = [(stub.text_start, &stub.text), (stub.data_start, &stub.data)]
+            .iter()
+            .map(|&(start, contents)| (start, start.saturating_add(contents.len() as u32)))
+            .collect();
+        Ok(())
+    }
+}
+
+// The ESP8266 ROM loader erases the wrong number of sectors when asked to
+// erase a region that doesn't start on a 64 kB block boundary. It erases the
+// sectors up to the next block boundary (the head) and then erases the
+// requested number of sectors *again* starting from the block boundary.
+// Compute the size to request so that the ROM ends up erasing `size` bytes.
+// https://docs.espressif.com/projects/esptool/en/latest/esp8266/advanced-topics/serial-protocol.html#erase-size-bug
+fn esp8266_rom_erase_size(flash_offset: u32, size: usize) -> u32 {
+    const SECTORS_PER_BLOCK: usize = 16;
+    let num_sectors = (size + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
+    let start_sector = flash_offset as usize / FLASH_SECTOR_SIZE;
+    let head_sectors = min(
+        SECTORS_PER_BLOCK - start_sector % SECTORS_PER_BLOCK,
+        num_sectors,
+    );
+    let sectors = if num_sectors < 2 * head_sectors {
+        (num_sectors + 1) / 2
+    } else {
+        num_sectors - head_sectors
+    };
+    (sectors * FLASH_SECTOR_SIZE) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::test_util::{connect, test_data, ALL_CHIPS};
+    use crate::sim::{DEFAULT_FLASH_SIZE, FLASH_MANUFACTURER_ID};
+
+    #[test]
+    fn test_detect_chip() -> Result<()> {
+        for chip in ALL_CHIPS {
+            let (_device, mut flasher) = connect(chip, false)?;
+            assert_eq!(flasher.flash_size()?, DEFAULT_FLASH_SIZE);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_flash_id() -> Result<()> {
+        let (_device, mut flasher) = connect(Chip::Esp32C3, false)?;
+        assert_eq!(flasher.flash_id()?, (FLASH_MANUFACTURER_ID, 0x4016));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chip_info() -> Result<()> {
+        for (chip, crystal) in [
+            (Chip::Esp8266, 26),
+            (Chip::Esp32, 40),
+            (Chip::Esp32C3, 40),
+            (Chip::Esp32H2, 32),
+        ] {
+            let (_device, mut flasher) = connect(chip, false)?;
+            assert_eq!(flasher.crystal_frequency()?, crystal);
+            flasher.change_baud_rate(921600)?;
+            assert_eq!(flasher.crystal_frequency()?, crystal);
+        }
+
+        let (device, mut flasher) = connect(Chip::Esp32, false)?;
+        device.write_memory(0x3FF5A004, 0x5678_9ABC);
+        device.write_memory(0x3FF5A008, 0x0000_1234);
+        device.write_memory(0x3FF5A00C, 1 << 15);
+        assert_eq!(flasher.mac_address()?, [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC]);
+        assert_eq!(flasher.chip_revision()?, Some((1, 0)));
+
+        // 40 MHz / 921600 = 43.40 which rounds to a divider of 43 + 6/16.
+        assert_eq!(flasher.achievable_baud_rate(921600)?, 922_190);
+        assert_eq!(flasher.achievable_baud_rate(40_000_000)?, 40_000_000);
+        assert_eq!(flasher.achievable_baud_rate(80_000_000)?, 40_000_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rom_write_flash() -> Result<()> {
+        let (device, mut flasher) = connect(Chip::Esp32, false)?;
+        let data = test_data(10_001);
+        flasher.write_flash(
+            0x10000,
+            &data,
+            WriteFlashOptions {
+                compress: false,
+                verify: true,
+                ..Default::default()
+            },
+        )?;
+        flasher.write_flash(
+            0x20000,
+            &data,
+            WriteFlashOptions {
+                verify: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(
+            &device.flash()[0x10000..0x10000 + data.len()],
+            data.as_slice()
+        );
+        assert_eq!(
+            &device.flash()[0x20000..0x20000 + data.len()],
+            data.as_slice()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_esp8266_rom_erase_size() -> Result<()> {
+        let (device, mut flasher) = connect(Chip::Esp8266, false)?;
+        device.set_flash(0, &vec![0; 0x40000]);
+        let data = test_data(0x8000);
+        flasher.write_flash(
+            0x1D000,
+            &data,
+            WriteFlashOptions {
+                compress: false,
+                ..Default::default()
+            },
+        )?;
+        let flash = device.flash();
+        assert_eq!(&flash[0x1D000..0x25000], data.as_slice());
+        assert!(flash[..0x1D000].iter().all(|&x| x == 0));
+        assert!(flash[0x25000..0x40000].iter().all(|&x| x == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_esp8266_rom_compress() -> Result<()> {
+        // The ROM loader rejects FLASH_DEFL_BEGIN so the data is sent
+        // uncompressed.
+        let (device, mut flasher) = connect(Chip::Esp8266, false)?;
+        let data = test_data(0x1000);
+        flasher.write_flash(0x10000, &data, WriteFlashOptions::default())?;
+        assert_eq!(&device.flash()[0x10000..0x11000], data.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_esp8266_rom_verify() -> Result<()> {
+        let (device, mut flasher) = connect(Chip::Esp8266, false)?;
+        device.set_flash(0, &vec![0; 0x1000]);
+        let data = test_data(0x1000);
+        assert!(matches!(
+            flasher.write_flash(
+                0,
+                &data,
+                WriteFlashOptions {
+                    compress: false,
+                    verify: true,
+                    ..Default::default()
+                }
+            ),
+            Err(Error::FlasherError(FlasherError::VerifyUnsupported))
+        ));
+        assert!(device.flash()[..0x1000].iter().all(|&x| x == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stub_read_write_erase() -> Result<()> {
+        let (device, mut flasher) = connect(Chip::Esp32S3, true)?;
+        let data = test_data(0x12345);
+        flasher.write_flash(
+            0x8000,
+            &data,
+            WriteFlashOptions {
+                verify: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(flasher.read_flash(0x8000, data.len() as u32)?, data);
+
+        flasher.erase_region(0x9000, 0x1000)?;
+        assert!(device.flash()[0x9000..0xA000].iter().all(|&x| x == 0xFF));
+        flasher.erase_flash()?;
+        assert!(device.flash().iter().all(|&x| x == 0xFF));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_unchanged() -> Result<()> {
+        let (device, mut flasher) = connect(Chip::Esp32, true)?;
+        let mut data = test_data(0x30000);
+        device.set_flash(0x10000, &data);
+        data[0x15000] ^= 0xFF;
+        let skipped = flasher.write_flash(
+            0x10000,
+            &data,
+            WriteFlashOptions {
+                verify: true,
+                skip_unchanged: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(skipped, 0x20000);
+        assert_eq!(&device.flash()[0x10000..0x40000], data.as_slice());
+
+        // Nothing needs to be written but the chip should still reboot.
+        let skipped = flasher.write_flash(
+            0x10000,
+            &data,
+            WriteFlashOptions {
+                verify: true,
+                skip_unchanged: true,
+                reboot: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(skipped, 0x30000);
+        assert!(device.is_app_running());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_ram() -> Result<()> {
+        use crate::image::{EspImage, EspImageSegment};
+
+        let (device, mut flasher) = connect(Chip::Esp32C3, false)?;
+        let mut image = EspImage::default();
+        image.header.entry_addr = 0x4038_0000;
+        image.segments.push(EspImageSegment {
+            load_addr: 0x4038_0000,
+            data: 0x1234_5678u32.to_le_bytes().to_vec(),
+        });
+        image.segments.push(EspImageSegment {
+            load_addr: 0x3FC8_0000,
+            data: 0x9ABC_DEF0u32.to_le_bytes().to_vec(),
+        });
+        flasher.load_ram(&image)?;
+        assert_eq!(device.read_memory(0x4038_0000), 0x1234_5678);
+        assert_eq!(device.read_memory(0x3FC8_0000), 0x9ABC_DEF0);
+
+        let (device, mut flasher) = connect(Chip::Esp32C3, false)?;
+        image.segments[0].data = vec![0xFF; 8];
+        image.segments.push(EspImageSegment {
+            load_addr: 0x4200_0000,
+            data: vec![0; 4],
+        });
+        assert!(flasher.load_ram(&image).is_err());
+        assert_eq!(device.read_memory(0x4038_0000), 0);
+
+        // The stub's text is at 0x40090000.
+        let (device, mut flasher) = connect(Chip::Esp32C3, true)?;
+        image.segments.truncate(1);
+        image.segments[0].load_addr = 0x4008_FFFC;
+        assert!(matches!(
+            flasher.load_ram(&image),
+            Err(Error::FlasherError(FlasherError::StubSegment(0x4008_FFFC)))
+        ));
+        assert_eq!(device.read_memory(0x4008_FFFC), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_write_mem() -> Result<()> {
+        let (device, mut flasher) = connect(Chip::Esp32, false)?;
+        flasher.write_mem(0x3FFB_0000, 0x1234_5678, 0xFFFF_FFFF, 0)?;
+        flasher.write_mem(0x3FFB_0004, 0x9ABC_DEF0, 0xFFFF_FFFF, 0)?;
+        flasher.write_mem(0x3FFB_0000, 0xAAAA_AAAA, 0x0000_FF00, 10)?;
+        assert_eq!(device.read_memory(0x3FFB_0000), 0x1234_AA78);
+        assert_eq!(flasher.read_mem(0x3FFB_0004)?, 0x9ABC_DEF0);
+        assert_eq!(
+            flasher.dump_mem(0x3FFB_0002, 5)?,
+            [0x34, 0x12, 0xF0, 0xDE, 0xBC]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bootloader_flash_params() -> Result<()> {
+        use crate::image::{EspImage, EspImageSegment, FlashFrequency, FlashMode, FlashParams};
+        use binrw::BinWrite;
+
+        let mut image = EspImage::default();
+        image.header.hash_appended = 1;
+        image.segments.push(EspImageSegment {
+            load_addr: 0x3FFF_0000,
+            data: test_data(0x100),
+        });
+        image.update_metadata();
+        let mut data: Vec<u8> = Vec::new();
+        image.write_to(&mut std::io::Cursor::new(&mut data))?;
+        let image_len = data.len();
+        data.extend(b"trailer!");
+
+        let (device, mut flasher) = connect(Chip::Esp32, false)?;
+        let params = FlashParams {
+            mode: Some(FlashMode::Dio),
+            frequency: Some(FlashFrequency::Mhz80),
+            size: None,
+        };
+        flasher.set_bootloader_flash_params(params, true);
+        flasher.write_flash(
+            0x1000,
+            &data,
+            WriteFlashOptions {
+                verify: true,
+                ..Default::default()
+            },
+        )?;
+        flasher.write_flash(
+            0x10000,
+            &data,
+            WriteFlashOptions {
+                verify: true,
+                ..Default::default()
+            },
+        )?;
+
+        let flash = device.flash();
+        let patched = EspImage::try_from(&flash[0x1000..0x1000 + data.len()])?;
+        assert_eq!(patched.header.spi_mode, 2);
+        assert_eq!(patched.header.spi_speed_size, 0x2F);
+        assert_eq!(patched.hash, Some(patched.compute_hash()));
+        assert_ne!(patched.hash, image.hash);
+        assert_eq!(&flash[0x1000 + image_len..0x1000 + data.len()], b"trailer!");
+        assert_eq!(&flash[0x10000..0x10000 + data.len()], data.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpatchable_bootloader() -> Result<()> {
+        use crate::image::{EspImage, EspImageSegment, FlashMode, FlashParams};
+        use binrw::BinWrite;
+
+        // An image with a secure boot v2 signature block in the next sector.
+        let mut image = EspImage::default();
+        image.header.hash_appended = 1;
+        image.segments.push(EspImageSegment {
+            load_addr: 0x3FFF_0000,
+            data: test_data(0x100),
+        });
+        image.update_metadata();
+        let mut signed: Vec<u8> = Vec::new();
+        image.write_to(&mut std::io::Cursor::new(&mut signed))?;
+        signed.resize(0x1000, 0xFF);
+        signed.extend([0xE7, 0x02, 0x00, 0x00]);
+        signed.resize(0x2000, 0xAA);
+
+        // Data that starts like an image but isn't one.
+        let mut garbage = test_data(0x400);
+        garbage[0] = 0xE9;
+        garbage[1] = 0xFF;
+
+        let (device, mut flasher) = connect(Chip::Esp32, false)?;
+        let params = FlashParams {
+            mode: Some(FlashMode::Dio),
+            ..Default::default()
+        };
+        flasher.set_bootloader_flash_params(params, false);
+        for data in [signed, garbage] {
+            assert!(flasher.bootloader_patch_error(0x1000, &data)?.is_some());
+            assert!(flasher.bootloader_patch_error(0x10000, &data)?.is_none());
+            flasher.write_flash(0x1000, &data, WriteFlashOptions::default())?;
+            assert_eq!(
+                &device.flash()[0x1000..0x1000 + data.len()],
+                data.as_slice()
+            );
+        }
         Ok(())
     }
 }
@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::{Seek, SeekFrom, Write};
+use std::borrow::Cow;
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::str::FromStr;
 
 use crate::{Error, Result};
 use binrw::{binrw, BinRead, BinReaderExt, BinWrite, ReadOptions, WriteOptions};
@@ -27,6 +29,258 @@ pub enum EspChipId {
     ESP32C3 = 0x0005,
     ESP32S3 = 0x0009,
     ESP32C2 = 0x000C,
+    ESP32C6 = 0x000D,
+    ESP32H2 = 0x0010,
+    ESP32P4 = 0x0012,
+}
+
+/// The SPI flash mode stored in the image header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashMode {
+    Qio,
+    Qout,
+    Dio,
+    Dout,
+}
+
+impl FlashMode {
+    pub fn encoding(self) -> u8 {
+        match self {
+            FlashMode::Qio => 0,
+            FlashMode::Qout => 1,
+            FlashMode::Dio => 2,
+            FlashMode::Dout => 3,
+        }
+    }
+}
+
+impl FromStr for FlashMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "qio" => FlashMode::Qio,
+            "qout" => FlashMode::Qout,
+            "dio" => FlashMode::Dio,
+            "dout" => FlashMode::Dout,
+            _ => return Err(Error::FormatError(format!("Unknown flash mode {s}"))),
+        })
+    }
+}
+
+impl std::fmt::Display for FlashMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FlashMode::Qio => "qio",
+            FlashMode::Qout => "qout",
+            FlashMode::Dio => "dio",
+            FlashMode::Dout => "dout",
+        })
+    }
+}
+
+/// The SPI flash frequency stored in the low nibble of the image header's
+/// `spi_speed_size` byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashFrequency {
+    Mhz80,
+    Mhz60,
+    Mhz48,
+    Mhz40,
+    Mhz30,
+    Mhz26,
+    Mhz24,
+    Mhz20,
+    Mhz16,
+    Mhz15,
+    Mhz12,
+}
+
+impl FlashFrequency {
+    /// The encoding of the frequency for `chip` or `None` if the chip does not
+    /// support it.
+    pub fn encoding(self, chip: Chip) -> Option<u8> {
+        // https://github.com/espressif/esptool/tree/master/esptool/targets
+        use FlashFrequency::*;
+        match (chip, self) {
+            (Chip::Esp32C2, Mhz60) => Some(0xF),
+            (Chip::Esp32C2, Mhz30) => Some(0x0),
+            (Chip::Esp32C2, Mhz20) => Some(0x1),
+            (Chip::Esp32C2, Mhz15) => Some(0x2),
+            (Chip::Esp32C2, _) => None,
+            (Chip::Esp32H2, Mhz48) => Some(0xF),
+            (Chip::Esp32H2, Mhz24) => Some(0x0),
+            (Chip::Esp32H2, Mhz16) => Some(0x1),
+            (Chip::Esp32H2, Mhz12) => Some(0x2),
+            (Chip::Esp32H2, _) => None,
+            (Chip::Esp32C6, Mhz80 | Mhz40) => Some(0x0),
+            (Chip::Esp32C6 | Chip::Esp32P4, Mhz20) => Some(0x2),
+            (Chip::Esp32C6 | Chip::Esp32P4, Mhz26) => None,
+            (_, Mhz80) => Some(0xF),
+            (_, Mhz40) => Some(0x0),
+            (_, Mhz26) => Some(0x1),
+            (_, Mhz20) => Some(0x2),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for FlashFrequency {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        use FlashFrequency::*;
+        Ok(match s {
+            "80m" => Mhz80,
+            "60m" => Mhz60,
+            "48m" => Mhz48,
+            "40m" => Mhz40,
+            "30m" => Mhz30,
+            "26m" => Mhz26,
+            "24m" => Mhz24,
+            "20m" => Mhz20,
+            "16m" => Mhz16,
+            "15m" => Mhz15,
+            "12m" => Mhz12,
+            _ => return Err(Error::FormatError(format!("Unknown flash frequency {s}"))),
+        })
+    }
+}
+
+impl std::fmt::Display for FlashFrequency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use FlashFrequency::*;
+        f.write_str(match self {
+            Mhz80 => "80m",
+            Mhz60 => "60m",
+            Mhz48 => "48m",
+            Mhz40 => "40m",
+            Mhz30 => "30m",
+            Mhz26 => "26m",
+            Mhz24 => "24m",
+            Mhz20 => "20m",
+            Mhz16 => "16m",
+            Mhz15 => "15m",
+            Mhz12 => "12m",
+        })
+    }
+}
+
+/// The SPI flash size stored in the high nibble of the image header's
+/// `spi_speed_size` byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashSize {
+    Kb256,
+    Kb512,
+    Mb1,
+    Mb2,
+    Mb4,
+    Mb8,
+    Mb16,
+    Mb32,
+    Mb64,
+    Mb128,
+}
+
+impl FlashSize {
+    const ALL: [FlashSize; 10] = [
+        FlashSize::Kb256,
+        FlashSize::Kb512,
+        FlashSize::Mb1,
+        FlashSize::Mb2,
+        FlashSize::Mb4,
+        FlashSize::Mb8,
+        FlashSize::Mb16,
+        FlashSize::Mb32,
+        FlashSize::Mb64,
+        FlashSize::Mb128,
+    ];
+
+    /// The flash size in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            FlashSize::Kb256 => 256 << 10,
+            FlashSize::Kb512 => 512 << 10,
+            FlashSize::Mb1 => 1 << 20,
+            FlashSize::Mb2 => 2 << 20,
+            FlashSize::Mb4 => 4 << 20,
+            FlashSize::Mb8 => 8 << 20,
+            FlashSize::Mb16 => 16 << 20,
+            FlashSize::Mb32 => 32 << 20,
+            FlashSize::Mb64 => 64 << 20,
+            FlashSize::Mb128 => 128 << 20,
+        }
+    }
+
+    pub fn from_bytes(bytes: usize) -> Option<Self> {
+        Self::ALL.iter().copied().find(|size| size.bytes() == bytes)
+    }
+
+    /// The encoding of the size for `chip` or `None` if the chip does not
+    /// support it.
+    pub fn encoding(self, chip: Chip) -> Option<u8> {
+        if chip == Chip::Esp8266 {
+            return match self {
+                FlashSize::Kb512 => Some(0x00),
+                FlashSize::Kb256 => Some(0x10),
+                FlashSize::Mb1 => Some(0x20),
+                FlashSize::Mb2 => Some(0x30),
+                FlashSize::Mb4 => Some(0x40),
+                FlashSize::Mb8 => Some(0x80),
+                FlashSize::Mb16 => Some(0x90),
+                _ => None,
+            };
+        }
+        match self {
+            FlashSize::Kb256 | FlashSize::Kb512 => None,
+            FlashSize::Mb1 => Some(0x00),
+            FlashSize::Mb2 => Some(0x10),
+            FlashSize::Mb4 => Some(0x20),
+            FlashSize::Mb8 => Some(0x30),
+            FlashSize::Mb16 => Some(0x40),
+            FlashSize::Mb32 => Some(0x50),
+            FlashSize::Mb64 => Some(0x60),
+            FlashSize::Mb128 => Some(0x70),
+        }
+    }
+}
+
+impl FromStr for FlashSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|size| size.to_string() == s)
+            .ok_or_else(|| Error::FormatError(format!("Unknown flash size {s}")))
+    }
+}
+
+impl std::fmt::Display for FlashSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.bytes();
+        if bytes < 1 << 20 {
+            f.write_fmt(format_args!("{}KB", bytes >> 10))
+        } else {
+            f.write_fmt(format_args!("{}MB", bytes >> 20))
+        }
+    }
+}
+
+/// Flash parameters to store in an image header. Parameters which are `None`
+/// are left unchanged.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct FlashParams {
+    pub mode: Option<FlashMode>,
+    pub frequency: Option<FlashFrequency>,
+    pub size: Option<FlashSize>,
+}
+
+impl FlashParams {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.frequency.is_none() && self.size.is_none()
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -41,10 +295,129 @@ pub struct EspImageHeader {
     pub spi_pin_drv: [u8; 3],
     pub chip_id: u16,
     pub min_chip_rev: u8,
-    pub reserved: [u8; 8],
+    /// The minimum chip revision as major * 100 + minor.
+    pub min_chip_rev_full: u16,
+    /// The maximum chip revision as major * 100 + minor.
+    pub max_chip_rev_full: u16,
+    pub reserved: [u8; 4],
     pub hash_appended: u8,
 }
 
+impl EspImageHeader {
+    /// Set the flash mode, frequency, and size for `chip`.
+    pub fn set_flash_params(&mut self, chip: Chip, params: &FlashParams) -> Result<()> {
+        if let Some(mode) = params.mode {
+            self.spi_mode = mode.encoding();
+        }
+        if let Some(freq) = params.frequency {
+            let encoding = freq.encoding(chip).ok_or_else(|| {
+                Error::FormatError(format!("Flash frequency {freq} is not supported by {chip}"))
+            })?;
+            self.spi_speed_size = (self.spi_speed_size & 0xF0) | encoding;
+        }
+        if let Some(size) = params.size {
+            let encoding = size.encoding(chip).ok_or_else(|| {
+                Error::FormatError(format!("Flash size {size} is not supported by {chip}"))
+            })?;
+            self.spi_speed_size = (self.spi_speed_size & 0x0F) | encoding;
+        }
+        Ok(())
+    }
+}
+
+/// The magic number at the start of `esp_app_desc_t`.
+pub const APP_DESC_MAGIC: u32 = 0xABCD5432;
+
+/// The ESP-IDF application description, `esp_app_desc_t`, at the start of
+/// the first DROM segment of an application image.
+#[derive(Debug, Clone)]
+#[binrw]
+#[brw(little, magic = 0xABCD5432u32)]
+pub struct EspAppDesc {
+    pub secure_version: u32,
+    pub reserved1: [u32; 2],
+    pub version: [u8; 32],
+    pub project_name: [u8; 32],
+    pub time: [u8; 16],
+    pub date: [u8; 16],
+    pub idf_ver: [u8; 32],
+    pub app_elf_sha256: [u8; 32],
+    /// The minimum eFuse block revision as major * 100 + minor.
+    pub min_efuse_blk_rev_full: u16,
+    /// The maximum eFuse block revision as major * 100 + minor.
+    pub max_efuse_blk_rev_full: u16,
+    /// The log2 of the MMU page size or 0 if unspecified.
+    pub mmu_page_size: u8,
+    pub reserved3: [u8; 3],
+    pub reserved2: [u32; 18],
+}
+
+/// Returns the string up to the first NUL byte.
+fn nul_terminated(data: &[u8]) -> Cow<'_, str> {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end])
+}
+
+/// Format a revision stored as major * 100 + minor.
+fn revision(rev: u16) -> String {
+    format!("v{}.{}", rev / 100, rev % 100)
+}
+
+impl EspAppDesc {
+    pub fn version(&self) -> Cow<'_, str> {
+        nul_terminated(&self.version)
+    }
+
+    pub fn project_name(&self) -> Cow<'_, str> {
+        nul_terminated(&self.project_name)
+    }
+
+    pub fn time(&self) -> Cow<'_, str> {
+        nul_terminated(&self.time)
+    }
+
+    pub fn date(&self) -> Cow<'_, str> {
+        nul_terminated(&self.date)
+    }
+
+    pub fn idf_ver(&self) -> Cow<'_, str> {
+        nul_terminated(&self.idf_ver)
+    }
+}
+
+impl std::fmt::Display for EspAppDesc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Project name: {}\n", self.project_name()))?;
+        f.write_fmt(format_args!("App version: {}\n", self.version()))?;
+        f.write_fmt(format_args!(
+            "Compile time: {} {}\n",
+            self.date(),
+            self.time()
+        ))?;
+        f.write_fmt(format_args!("ESP-IDF: {}\n", self.idf_ver()))?;
+        f.write_fmt(format_args!("Secure version: {}\n", self.secure_version))?;
+        f.write_fmt(format_args!(
+            "Minimum eFuse block revision: {}\n",
+            revision(self.min_efuse_blk_rev_full)
+        ))?;
+        f.write_fmt(format_args!(
+            "Maximum eFuse block revision: {}\n",
+            revision(self.max_efuse_blk_rev_full)
+        ))?;
+        if self.mmu_page_size != 0 && self.mmu_page_size < 32 {
+            f.write_fmt(format_args!(
+                "MMU page size: {} KB\n",
+                (1u32 << self.mmu_page_size) >> 10
+            ))?;
+        }
+        f.write_str("ELF file SHA256: ")?;
+        for &x in &self.app_elf_sha256 {
+            f.write_fmt(format_args!("{:02x}", x))?;
+        }
+        Ok(())
+    }
+}
+
 #[binrw]
 #[brw(little)]
 pub struct EspImageSegment {
@@ -136,6 +509,14 @@ impl std::fmt::Display for EspImage {
         } else {
             f.write_fmt(format_args!("Chip Id: 0x{:04X}\n", self.header.chip_id))?;
         }
+        f.write_fmt(format_args!(
+            "Minimum chip revision: {}\n",
+            revision(self.header.min_chip_rev_full)
+        ))?;
+        f.write_fmt(format_args!(
+            "Maximum chip revision: {}\n",
+            revision(self.header.max_chip_rev_full)
+        ))?;
 
         f.write_fmt(format_args!(
             "{} segment{}\n\n",
@@ -179,6 +560,10 @@ impl std::fmt::Display for EspImage {
             f.write_fmt(format_args!(" ({valid})"))?;
         }
 
+        if let Some(app_desc) = self.app_desc() {
+            f.write_fmt(format_args!("\n\nApplication information:\n{app_desc}"))?;
+        }
+
         Ok(())
     }
 }
@@ -239,17 +624,107 @@ impl EspImage {
         hasher.hasher.finalize().into()
     }
 
+    /// The application description from the first segment which starts with
+    /// one, normally the first DROM segment of an ESP-IDF application.
+    pub fn app_desc(&self) -> Option<EspAppDesc> {
+        self.segments
+            .iter()
+            .find(|seg| seg.data.starts_with(&APP_DESC_MAGIC.to_le_bytes()))
+            .and_then(|seg| EspAppDesc::read(&mut Cursor::new(&seg.data)).ok())
+    }
+
     /**
-     * Update the image's segment count, checksum, and hash.
+     * Update the image's segment count, checksum, and, if the header says one
+     * is appended, hash.
      */
     pub fn update_metadata(&mut self) {
         self.header.segment_count = self.segments.len().try_into().unwrap();
-        self.header.hash_appended = 1;
         self.checksum = self.compute_checksum();
-        self.hash = Some(self.compute_hash());
+        self.hash = if self.header.hash_appended != 0 {
+            Some(self.compute_hash())
+        } else {
+            None
+        };
     }
 }
 
+// The size of the sectors that secure boot v2 signature blocks are aligned to.
+const SIGNATURE_SECTOR_SIZE: usize = 0x1000;
+
+/// Returns true if `trailer`, the data following an image of `image_len`
+/// bytes, starts with a secure boot signature.
+fn is_signed(image_len: usize, trailer: &[u8]) -> bool {
+    // Secure boot v1 appends a 4-byte version of 0 and a 64-byte ECDSA
+    // signature directly after the image.
+    if trailer.len() == 68 && trailer[..4] == [0; 4] {
+        return true;
+    }
+    // Secure boot v2 pads the image to a sector boundary and appends a
+    // signature block starting with the magic byte 0xE7 and a version of 2
+    // (RSA) or 3 (ECDSA).
+    let padding =
+        (SIGNATURE_SECTOR_SIZE - image_len % SIGNATURE_SECTOR_SIZE) % SIGNATURE_SECTOR_SIZE;
+    matches!(
+        trailer.get(padding..padding + 2),
+        Some([0xE7, 0x02]) | Some([0xE7, 0x03])
+    )
+}
+
+/// Check that the flash parameters of the image at the start of `data` can be
+/// rewritten by `patch_flash_params`. Data that isn't a valid image, such as
+/// an encrypted bootloader, and signed images, whose signature patching would
+/// break, should be written unmodified instead.
+pub fn check_patchable(chip: Chip, data: &[u8]) -> Result<()> {
+    if chip == Chip::Esp8266 {
+        if data.len() < 8 || data[0] != 0xE9 {
+            return Err(Error::FormatError("Not an ESP8266 image".into()));
+        }
+        return Ok(());
+    }
+
+    let mut cursor = Cursor::new(data);
+    let image = EspImage::read(&mut cursor)?;
+    if image.checksum != image.compute_checksum() {
+        return Err(Error::FormatError("The image checksum is invalid".into()));
+    }
+    let end = cursor.position() as usize;
+    if is_signed(end, &data[end..]) {
+        return Err(Error::FormatError("The image is signed".into()));
+    }
+    Ok(())
+}
+
+/// Rewrite the flash parameters in the header of the image at the start of
+/// `data`, like esptool does when writing a bootloader. Any data following the
+/// image is preserved. Fails if `check_patchable` does.
+pub fn patch_flash_params(chip: Chip, data: &[u8], params: &FlashParams) -> Result<Vec<u8>> {
+    check_patchable(chip, data)?;
+    if chip == Chip::Esp8266 {
+        // ESP8266 images have no extended header or hash so only the two
+        // bytes need to change.
+        let mut header = EspImageHeader {
+            spi_mode: data[2],
+            spi_speed_size: data[3],
+            ..Default::default()
+        };
+        header.set_flash_params(chip, params)?;
+        let mut patched = data.to_vec();
+        patched[2] = header.spi_mode;
+        patched[3] = header.spi_speed_size;
+        return Ok(patched);
+    }
+
+    let mut cursor = Cursor::new(data);
+    let mut image = EspImage::read(&mut cursor)?;
+    let end = cursor.position() as usize;
+    image.header.set_flash_params(chip, params)?;
+    image.update_metadata();
+    let mut patched: Vec<u8> = Vec::with_capacity(data.len());
+    image.write_to(&mut Cursor::new(&mut patched))?;
+    patched.extend_from_slice(&data[end..]);
+    Ok(patched)
+}
+
 #[cfg(test)]
 mod test {
     use binrw::BinWrite;
@@ -291,10 +766,57 @@ mod test {
             spi_pin_drv: [0; 3],
             chip_id: 0x0,
             min_chip_rev: 0x0,
-            reserved: [0; 8],
+            min_chip_rev_full: 0x0,
+            max_chip_rev_full: 399,
+            reserved: [0; 4],
             hash_appended: 0x1,
         })?;
-        assert_eq!(&data, b"\xe9\x06\x00\x00\xF0\x1C\x08\x40\xEE\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01");
+        assert_eq!(&data, b"\xe9\x06\x00\x00\xF0\x1C\x08\x40\xEE\x00\x00\x00\x00\x00\x00\x00\x00\x8F\x01\x00\x00\x00\x00\x01");
+        Ok(())
+    }
+
+    #[test]
+    fn test_app_desc() -> Result<()> {
+        let mut desc = APP_DESC_MAGIC.to_le_bytes().to_vec();
+        desc.extend(3u32.to_le_bytes());
+        desc.resize(16, 0);
+        for (field, len) in [
+            (&b"v1.2.3"[..], 32),
+            (b"hello_world", 32),
+            (b"12:34:56", 16),
+            (b"Jan  1 2024", 16),
+            (b"v5.1", 32),
+        ] {
+            let start = desc.len();
+            desc.extend(field);
+            desc.resize(start + len, 0);
+        }
+        desc.extend([0xAB; 32]);
+        desc.extend(1u16.to_le_bytes());
+        desc.extend(199u16.to_le_bytes());
+        desc.push(16);
+        desc.resize(256, 0);
+        desc.extend(b"rodata");
+
+        let mut image = EspImage::default();
+        image.segments.push(EspImageSegment {
+            load_addr: 0x3F40_0020,
+            data: desc,
+        });
+        let app_desc = image.app_desc().expect("missing app description");
+        assert_eq!(app_desc.secure_version, 3);
+        assert_eq!(app_desc.version(), "v1.2.3");
+        assert_eq!(app_desc.project_name(), "hello_world");
+        assert_eq!(app_desc.time(), "12:34:56");
+        assert_eq!(app_desc.date(), "Jan  1 2024");
+        assert_eq!(app_desc.idf_ver(), "v5.1");
+        assert_eq!(app_desc.app_elf_sha256, [0xAB; 32]);
+        assert_eq!(app_desc.min_efuse_blk_rev_full, 1);
+        assert_eq!(app_desc.max_efuse_blk_rev_full, 199);
+        assert_eq!(app_desc.mmu_page_size, 16);
+
+        image.segments[0].data[0] = 0;
+        assert!(image.app_desc().is_none());
         Ok(())
     }
 }
@@ -15,19 +15,29 @@
 
 mod chip;
 mod command;
+pub mod coredump;
+pub mod efuse;
 mod elf;
 pub mod event;
 mod flasher;
 pub mod image;
 pub mod partition;
 pub mod protocol;
-mod stub;
+pub mod reset;
+pub mod rfc2217;
+pub mod security;
+pub mod session;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod stub;
+pub mod transport;
 
 pub use chip::Chip;
 use command::CommandError;
-pub use elf::elf_to_image;
-pub use flasher::Flasher;
+pub use elf::{elf_to_image, ElfDebugInfo, SourceLocation};
 use flasher::FlasherError;
+pub use flasher::{Flasher, WriteFlashOptions};
+pub use transport::Transport;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -88,7 +98,7 @@ impl<T> timeout::ErrorExt for Result<T, Error> {
 fn from_le(data: &[u8]) -> u32 {
     debug_assert!(data.len() <= 4);
     let mut le_data = [0u8; 4];
-    (&mut le_data[..data.len()]).copy_from_slice(data);
+    le_data[..data.len()].copy_from_slice(data);
     u32::from_le_bytes(le_data)
 }
 
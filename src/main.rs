@@ -13,17 +13,29 @@
 // limitations under the License.
 
 use std::borrow::Cow;
+use std::cmp::min;
+use std::ffi::OsStr;
 use std::path::PathBuf;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use binrw::BinWrite;
 use clap::{arg, command, ArgMatches, Command};
 
-use espflashtool::event::EventTracer;
-use espflashtool::image::EspImage;
+use espflashtool::event::{EventTracer, HexDump};
+use espflashtool::image::{EspImage, FlashParams};
 use espflashtool::partition::EspPartitionTable;
-use espflashtool::{elf_to_image, Chip, Flasher};
-// use espflashtool::timeout::ErrorExt;
+use espflashtool::session::SessionRecorder;
+use espflashtool::stub::Stub;
+use espflashtool::timeout::ErrorExt;
+use espflashtool::{elf_to_image, Chip, ElfDebugInfo, Flasher, WriteFlashOptions};
+
+const FLASH_MODES: [&str; 4] = ["qio", "qout", "dio", "dout"];
+const FLASH_FREQUENCIES: [&str; 11] = [
+    "80m", "60m", "48m", "40m", "30m", "26m", "24m", "20m", "16m", "15m", "12m",
+];
+const FLASH_SIZES: [&str; 10] = [
+    "256KB", "512KB", "1MB", "2MB", "4MB", "8MB", "16MB", "32MB", "64MB", "128MB",
+];
 
 fn arguments() -> ArgMatches {
     command!()
@@ -39,18 +51,37 @@ fn arguments() -> ArgMatches {
             arg!(-c --chip <CHIP> "ESP chip")
                 .required(false)
                 .global(true)
-                .possible_values(["esp8266", "esp32", "esp32s2", "esp32s3", "esp32c3"]),
+                .possible_values([
+                    "esp8266", "esp32", "esp32s2", "esp32s3", "esp32c3", "esp32c2", "esp32c6",
+                    "esp32h2", "esp32p4",
+                ]),
         )
         .arg(
-            arg!(-p --port <PORT> "Path to serial port")
+            arg!(-p --port <PORT> "Path to serial port, rfc2217://HOST:PORT, or socket://HOST:PORT")
                 .required(false)
                 .global(true),
         )
         .arg(
-            arg!(-s --stub <STUB> "Path to stub")
+            arg!(--record <PATH> "Record the serial session to a file")
+                .required(false)
+                .global(true)
+                .allow_invalid_utf8(true),
+        )
+        .arg(
+            arg!(--reset <STRATEGY> "How to reset the chip: classic, usb-jtag-serial, unix-tight, no-reset, or a sequence like D0|R1|W100|D1|R0|W50|D0")
                 .required(false)
                 .global(true),
         )
+        .arg(
+            arg!(-s --stub <STUB> "Path to stub; overrides the bundled stub")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"no-stub" "Use the ROM loader rather than the bundled stub")
+                .global(true)
+                .conflicts_with("stub"),
+        )
         .arg(
             arg!(-t --trace [PROTOCOL] ... "Trace serial communication")
                 .default_missing_value("all")
@@ -65,7 +96,134 @@ fn arguments() -> ArgMatches {
         )
         .subcommand(Command::new("detect-chip").about("Detects the type of the ESP chip"))
         .subcommand(Command::new("list-ports").about("List serial ports"))
+        .subcommand(
+            Command::new("chip-info")
+                .about("Print the chip type, revision, crystal frequency, MAC, and flash"),
+        )
         .subcommand(Command::new("flash-id").about("Print the flash ID"))
+        .subcommand(
+            Command::new("efuse-summary")
+                .about("Print the eFuses")
+                .arg(arg!(--json "Print the eFuses as JSON")),
+        )
+        .subcommand(
+            Command::new("security-info")
+                .about("Print the security info (ESP32-S2 and later)"),
+        )
+        .subcommand(
+            Command::new("write-flash")
+                .about("Write files to flash")
+                .arg(
+                    arg!(<ADDR_FILE> "Pairs of flash addresses and paths to the files to write")
+                        .required(true)
+                        .multiple_values(true)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(arg!(--"no-compress" "Send the data uncompressed"))
+                .arg(arg!(--verify "Verify the flash contents after writing"))
+                .arg(arg!(--"skip-unchanged" "Only write 64 kB blocks whose contents differ"))
+                .arg(arg!(--"no-reboot" "Do not reset the chip after writing"))
+                .arg(
+                    arg!(--"flash-mode" <MODE> "Set the flash mode in the bootloader header")
+                        .required(false)
+                        .possible_values(FLASH_MODES),
+                )
+                .arg(
+                    arg!(--"flash-freq" <FREQ> "Set the flash frequency in the bootloader header")
+                        .required(false)
+                        .possible_values(FLASH_FREQUENCIES),
+                )
+                .arg(
+                    arg!(--"flash-size" <SIZE> "Set the flash size in the bootloader header")
+                        .required(false)
+                        .possible_values(["keep", "detect"])
+                        .possible_values(FLASH_SIZES),
+                ),
+        )
+        .subcommand(
+            Command::new("read-flash")
+                .about("Read flash to a file (requires the stub)")
+                .arg(arg!(<ADDR> "Flash address to start reading from").required(true))
+                .arg(arg!(<SIZE> "Number of bytes to read").required(true))
+                .arg(
+                    arg!(<OUTPUT_PATH> "Path to write the data to")
+                        .required(true)
+                        .allow_invalid_utf8(true),
+                ),
+        )
+        .subcommand(Command::new("erase-flash").about("Erase the entire flash"))
+        .subcommand(
+            Command::new("erase-region")
+                .about("Erase a region of flash")
+                .arg(
+                    arg!(<ADDR> "Flash address of the region; must be a multiple of 4096")
+                        .required(true),
+                )
+                .arg(arg!(<SIZE> "Size of the region; must be a multiple of 4096").required(true)),
+        )
+        .subcommand(
+            Command::new("read-mem")
+                .about("Read 32-bit words of memory")
+                .arg(arg!(<ADDR> "Address to read").required(true))
+                .arg(arg!([COUNT] "Number of words to read").default_value("1")),
+        )
+        .subcommand(
+            Command::new("write-mem")
+                .about("Write a 32-bit word of memory")
+                .arg(arg!(<ADDR> "Address to write").required(true))
+                .arg(arg!(<VALUE> "Value to write").required(true))
+                .arg(arg!([MASK] "Only write the bits set in the mask").default_value("0xFFFFFFFF"))
+                .arg(
+                    arg!(--delay <MICROSECONDS> "Time to wait after writing")
+                        .required(false)
+                        .default_value("0"),
+                ),
+        )
+        .subcommand(
+            Command::new("dump-mem")
+                .about("Dump a region of memory to a file")
+                .arg(arg!(<ADDR> "Address to start reading from").required(true))
+                .arg(arg!(<SIZE> "Number of bytes to read").required(true))
+                .arg(
+                    arg!(<OUTPUT_PATH> "Path to write the data to")
+                        .required(true)
+                        .allow_invalid_utf8(true),
+                ),
+        )
+        .subcommand(
+            Command::new("coredump")
+                .about("Read the core dump from flash and print the tasks (requires the stub)")
+                .arg(
+                    arg!([OUTPUT_PATH] "Path to write the ELF core file to")
+                        .required(false)
+                        .allow_invalid_utf8(true),
+                ),
+        )
+        .subcommand(
+            Command::new("load-ram")
+                .about("Load an ELF file or ESP image into RAM and run it")
+                .arg(
+                    arg!(<PATH> "Path to the ELF file or image")
+                        .required(true)
+                        .allow_invalid_utf8(true),
+                ),
+        )
+        .subcommand(
+            Command::new("monitor")
+                .about("Reset into the application and print its output")
+                .long_about(
+                    "Reset into the application and print its output. Keys typed on stdin are \
+                     sent to the device as they are typed. Type Ctrl-] followed by r to reset the \
+                     application, b to reset into the bootloader, q to quit, or Ctrl-] to send \
+                     Ctrl-] itself.",
+                )
+                .arg(arg!(--timestamps "Prefix each line with the time since the monitor started"))
+                .arg(
+                    arg!(--elf <ELF_PATH> "Annotate code addresses using the symbols and line info in the ELF file")
+                        .required(false)
+                        .allow_invalid_utf8(true),
+                ),
+        )
         .subcommand(
             Command::new("image-info")
                 .about("Display information about an ESP image")
@@ -96,15 +254,66 @@ fn arguments() -> ArgMatches {
                     arg!([OUTPUT_PATH] "Output path; defaults to <ELF_PATH>.bin")
                         .required(false)
                         .allow_invalid_utf8(true),
+                )
+                .arg(
+                    arg!(--"flash-mode" <MODE> "Flash mode")
+                        .required(false)
+                        .possible_values(FLASH_MODES),
+                )
+                .arg(
+                    arg!(--"flash-freq" <FREQ> "Flash frequency")
+                        .required(false)
+                        .possible_values(FLASH_FREQUENCIES),
+                )
+                .arg(
+                    arg!(--"flash-size" <SIZE> "Flash size")
+                        .required(false)
+                        .possible_values(FLASH_SIZES),
+                ),
+        )
+        .subcommand(
+            Command::new("stub")
+                .about("Convert and inspect flasher stubs")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("convert")
+                        .about("Convert an esptool JSON stub or an ELF stub build to a stub file (requires --chip)")
+                        .arg(
+                            arg!(<INPUT_PATH> "Path to the JSON or ELF file")
+                                .required(true)
+                                .allow_invalid_utf8(true),
+                        )
+                        .arg(
+                            arg!(<OUTPUT_PATH> "Path to write the stub to")
+                                .required(true)
+                                .allow_invalid_utf8(true),
+                        ),
+                )
+                .subcommand(
+                    Command::new("info")
+                        .about("Display information about a stub file")
+                        .arg(
+                            arg!(<STUB_PATH> "Path to the stub")
+                                .required(true)
+                                .allow_invalid_utf8(true),
+                        ),
                 ),
         )
         .get_matches()
 }
 
-fn open_connection(args: &ArgMatches) -> Result<Flasher> {
-    use std::str::FromStr;
+// Open the port without connecting to the loader.
+fn open_port(args: &ArgMatches) -> Result<Flasher> {
     let port = args.value_of("port").unwrap_or("/dev/tty.SLAB_USBtoUART");
     let mut flasher = Flasher::new(port)?;
+    if let Some(strategy) = args.value_of("reset") {
+        flasher.set_reset_strategy(strategy.parse()?);
+    }
+    if let Some(path) = args.value_of_os("record") {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create {}", path.to_string_lossy()))?;
+        flasher.add_observer(SessionRecorder::new(file));
+    }
     if args.is_present("trace") {
         let mut serial = false;
         let mut line = false;
@@ -137,6 +346,12 @@ fn open_connection(args: &ArgMatches) -> Result<Flasher> {
             }
         }));
     }
+    Ok(flasher)
+}
+
+fn open_connection(args: &ArgMatches) -> Result<Flasher> {
+    use std::str::FromStr;
+    let mut flasher = open_port(args)?;
     // Read the stub before connecting.
     let stub = if let Some(path) = args.value_of("stub") {
         Some(std::fs::read(path)?)
@@ -146,14 +361,232 @@ fn open_connection(args: &ArgMatches) -> Result<Flasher> {
     flasher.connect()?;
     if let Some(stub) = stub {
         flasher.run_stub(&stub)?;
+    } else if !args.is_present("no-stub") {
+        flasher.run_bundled_stub()?;
     }
     if let Some(rate) = args.value_of("baud") {
         let rate: u32 = u32::from_str(rate)?;
+        let actual = flasher.achievable_baud_rate(rate)?;
+        // UARTs generally tolerate a few percent of error.
+        if actual.abs_diff(rate) as f64 > rate as f64 * 0.025 {
+            eprintln!(
+                "Warning: {rate} baud is not achievable with a {} MHz crystal; the chip will use about {actual} baud",
+                flasher.crystal_frequency()?
+            );
+        }
         flasher.change_baud_rate(rate)?;
     }
     Ok(flasher)
 }
 
+// The key that starts a monitor command.
+const MONITOR_MENU_KEY: u8 = 0x1D; // Ctrl-]
+
+// Turns off line buffering, echo, and signal keys on the terminal, if stdin
+// is one, until dropped so that each key is sent to the device immediately.
+#[cfg(unix)]
+struct RawTerminal(Option<libc::termios>);
+
+#[cfg(unix)]
+impl RawTerminal {
+    fn new() -> Self {
+        // SAFETY: termios is plain data and the calls only read or write it.
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::isatty(libc::STDIN_FILENO) == 0
+                || libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0
+            {
+                return RawTerminal(None);
+            }
+            let saved = termios;
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return RawTerminal(None);
+            }
+            RawTerminal(Some(saved))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(saved) = self.0 {
+            // SAFETY: restores the settings read by `new`.
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved);
+            }
+        }
+    }
+}
+
+// Other platforms keep the console's line buffering.
+#[cfg(not(unix))]
+struct RawTerminal;
+
+#[cfg(not(unix))]
+impl RawTerminal {
+    fn new() -> Self {
+        RawTerminal
+    }
+}
+
+// The addresses written as 0x followed by exactly 8 hex digits in `line`.
+fn addresses(line: &str) -> impl Iterator<Item = u32> + '_ {
+    line.match_indices("0x").filter_map(move |(idx, _)| {
+        let digits = &line[idx + 2..];
+        let len = digits.bytes().take_while(|b| b.is_ascii_hexdigit()).count();
+        if len == 8 {
+            u32::from_str_radix(&digits[..8], 16).ok()
+        } else {
+            None
+        }
+    })
+}
+
+fn monitor(args: &ArgMatches, timestamps: bool, debug_info: Option<&ElfDebugInfo>) -> Result<()> {
+    use std::io::{Read, Write};
+    use std::sync::mpsc::{channel, TryRecvError};
+    use std::time::{Duration, Instant};
+
+    let mut flasher = open_port(args)?;
+    if let Some(rate) = args.value_of("baud") {
+        flasher.set_port_baud_rate(parse_int(rate)?)?;
+    }
+    flasher.reset(false)?;
+
+    // Read stdin on its own thread so reading from the device never blocks.
+    let _terminal = RawTerminal::new();
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut stdin = stdin.lock();
+        let mut buf = [0u8; 256];
+        while let Ok(size) = stdin.read(&mut buf) {
+            if size == 0 || sender.send(buf[..size].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let mut menu = false;
+    // The number of bytes of the current line that have been printed.
+    let mut printed = 0;
+    'monitor: loop {
+        match receiver.try_recv() {
+            Ok(input) => {
+                let mut output = Vec::with_capacity(input.len());
+                for byte in input {
+                    if menu {
+                        menu = false;
+                        match byte {
+                            b'r' => flasher.reset(false)?,
+                            b'b' => flasher.reset(true)?,
+                            b'q' => break 'monitor,
+                            MONITOR_MENU_KEY => output.push(byte),
+                            _ => eprintln!(
+                                "Unknown command; use Ctrl-] followed by r, b, q, or Ctrl-]"
+                            ),
+                        }
+                    } else if byte == MONITOR_MENU_KEY {
+                        // Send what was typed before the command first.
+                        if !output.is_empty() {
+                            flasher.write_raw(&output)?;
+                            output.clear();
+                        }
+                        menu = true;
+                    } else if byte == b'\n' {
+                        output.extend(b"\r\n");
+                    } else {
+                        output.push(byte);
+                    }
+                }
+                if !output.is_empty() {
+                    flasher.write_raw(&output)?;
+                }
+            }
+            Err(TryRecvError::Empty) => (),
+            Err(TryRecvError::Disconnected) => break,
+        }
+        let line = match flasher.read_line(Duration::from_millis(50)) {
+            Ok(line) => line,
+            Err(err) if err.is_timeout() => {
+                // Show a partial line, such as a prompt or the echo of what
+                // was typed, right away. A trailing carriage return is held
+                // back until the rest of the line arrives.
+                let partial = flasher.partial_line();
+                let end = partial.len() - partial.ends_with(b"\r") as usize;
+                if end > printed {
+                    let mut stdout = std::io::stdout();
+                    if printed == 0 && timestamps {
+                        write!(stdout, "[{:10.3}] ", start.elapsed().as_secs_f64())?;
+                    }
+                    stdout.write_all(&partial[printed..end])?;
+                    stdout.flush()?;
+                    printed = end;
+                }
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if line.is_empty() {
+            // The connection was closed.
+            break;
+        }
+        let mut end = line.len();
+        while end > 0 && matches!(line[end - 1], b'\r' | b'\n') {
+            end -= 1;
+        }
+        let mut stdout = std::io::stdout();
+        if printed == 0 && timestamps {
+            write!(stdout, "[{:10.3}] ", start.elapsed().as_secs_f64())?;
+        }
+        stdout.write_all(&line[printed.min(end)..end])?;
+        writeln!(stdout)?;
+        printed = 0;
+        if let Some(debug_info) = debug_info {
+            for addr in addresses(&String::from_utf8_lossy(&line[..end])) {
+                if let Some(location) = debug_info.lookup(addr) {
+                    println!("  0x{addr:08X}: {location}");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The flash parameters given by --flash-mode, --flash-freq, and --flash-size.
+/// A flash size of "keep" or "detect" leaves the size unset.
+fn flash_params(sub_args: &ArgMatches) -> Result<FlashParams> {
+    let mut params = FlashParams::default();
+    if let Some(mode) = sub_args.value_of("flash-mode") {
+        params.mode = Some(mode.parse()?);
+    }
+    if let Some(freq) = sub_args.value_of("flash-freq") {
+        params.frequency = Some(freq.parse()?);
+    }
+    match sub_args.value_of("flash-size") {
+        None | Some("keep") | Some("detect") => (),
+        Some(size) => params.size = Some(size.parse()?),
+    }
+    Ok(params)
+}
+
+fn parse_int(value: &str) -> Result<u32> {
+    let result = if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        u32::from_str_radix(hex, 16)
+    } else {
+        value.parse()
+    };
+    result.with_context(|| format!("Invalid number: {value}"))
+}
+
 fn main() -> Result<()> {
     let args = arguments();
     let (subcmd, sub_args) = args.subcommand().unwrap();
@@ -168,6 +601,29 @@ fn main() -> Result<()> {
             let ports = serialport::available_ports().context("Failed to detect serial ports")?;
             println!("{ports:#?}");
         }
+        "chip-info" => {
+            let mut flasher = open_connection(&args)?;
+            let chip = flasher.chip()?;
+            let efuses = flasher.read_efuses()?;
+            println!("Chip: {chip}");
+            if let Some((major, minor)) = efuses.chip_revision() {
+                println!("Revision: v{major}.{minor}");
+            }
+            if let Some(package) = efuses.package_version() {
+                println!("Package version: {package}");
+            }
+            println!("Crystal: {} MHz", flasher.crystal_frequency()?);
+            if let Some(mac) = efuses.mac() {
+                let mac: Vec<String> = mac.iter().map(|b| format!("{b:02x}")).collect();
+                println!("MAC: {}", mac.join(":"));
+            }
+            let (mid, did) = flasher.flash_id()?;
+            println!("Flash manufacturer ID: {mid:02X}");
+            println!("Flash device ID: {did:04X}");
+            let flash_size = flasher.flash_size()? as f64 / 1048576.0;
+            println!("Flash size: {flash_size} MB");
+            flasher.reset(false)?;
+        }
         "flash-id" => {
             let mut flasher = open_connection(&args)?;
             let (mid, did) = flasher.flash_id()?;
@@ -177,6 +633,162 @@ fn main() -> Result<()> {
             println!("Flash size: {flash_size} MB");
             flasher.reset(false)?;
         }
+        "efuse-summary" => {
+            let mut flasher = open_connection(&args)?;
+            let efuses = flasher.read_efuses()?;
+            if sub_args.is_present("json") {
+                print!("{}", efuses.to_json());
+            } else {
+                print!("{efuses}");
+            }
+            flasher.reset(false)?;
+        }
+        "security-info" => {
+            let mut flasher = open_connection(&args)?;
+            let info = flasher.security_info()?;
+            print!("{info}");
+            flasher.reset(false)?;
+        }
+        "write-flash" => {
+            let values: Vec<&OsStr> = sub_args.values_of_os("ADDR_FILE").unwrap().collect();
+            if values.len() % 2 != 0 {
+                bail!("Expected pairs of flash addresses and file paths");
+            }
+            // Read all of the files before connecting.
+            let mut writes: Vec<(u32, Vec<u8>)> = Vec::with_capacity(values.len() / 2);
+            for pair in values.chunks(2) {
+                let addr = pair[0]
+                    .to_str()
+                    .with_context(|| format!("Invalid flash address: {:?}", pair[0]))?;
+                let addr = parse_int(addr)?;
+                let data = std::fs::read(pair[1])
+                    .with_context(|| format!("Unable to read {:?}", pair[1]))?;
+                writes.push((addr, data));
+            }
+            let options = WriteFlashOptions {
+                compress: !sub_args.is_present("no-compress"),
+                verify: sub_args.is_present("verify"),
+                skip_unchanged: sub_args.is_present("skip-unchanged"),
+                reboot: false,
+            };
+            let params = flash_params(sub_args)?;
+            let detect_size = sub_args.value_of("flash-size") == Some("detect");
+            let mut flasher = open_connection(&args)?;
+            flasher.set_bootloader_flash_params(params, detect_size);
+            for (addr, data) in writes {
+                if let Some(err) = flasher.bootloader_patch_error(addr, &data)? {
+                    eprintln!(
+                        "Warning: writing the bootloader at 0x{addr:08X} without changing its flash parameters: {err}"
+                    );
+                }
+                let skipped = flasher.write_flash(addr, &data, options)?;
+                if skipped > 0 {
+                    println!(
+                        "Wrote {} bytes at 0x{addr:08X} ({skipped} unchanged bytes skipped)",
+                        data.len() - min(skipped, data.len())
+                    );
+                } else {
+                    println!("Wrote {} bytes at 0x{addr:08X}", data.len());
+                }
+            }
+            if !sub_args.is_present("no-reboot") {
+                flasher.reset(false)?;
+            }
+        }
+        "read-flash" => {
+            let addr = parse_int(sub_args.value_of("ADDR").unwrap())?;
+            let size = parse_int(sub_args.value_of("SIZE").unwrap())?;
+            let path = sub_args.value_of_os("OUTPUT_PATH").unwrap();
+            let mut flasher = open_connection(&args)?;
+            let data = flasher.read_flash(addr, size)?;
+            std::fs::write(path, &data).context("Unable to write output file")?;
+            println!("Read {} bytes at 0x{addr:08X}", data.len());
+            flasher.reset(false)?;
+        }
+        "erase-flash" => {
+            let mut flasher = open_connection(&args)?;
+            flasher.erase_flash()?;
+            println!("Erased flash");
+            flasher.reset(false)?;
+        }
+        "erase-region" => {
+            let addr = parse_int(sub_args.value_of("ADDR").unwrap())?;
+            let size = parse_int(sub_args.value_of("SIZE").unwrap())?;
+            let mut flasher = open_connection(&args)?;
+            flasher.erase_region(addr, size)?;
+            println!("Erased {size} bytes at 0x{addr:08X}");
+            flasher.reset(false)?;
+        }
+        "read-mem" => {
+            let addr = parse_int(sub_args.value_of("ADDR").unwrap())?;
+            let count = parse_int(sub_args.value_of("COUNT").unwrap())?;
+            let mut flasher = open_connection(&args)?;
+            for idx in 0..count {
+                let word_addr = addr.wrapping_add(4 * idx);
+                let value = flasher.read_mem(word_addr)?;
+                println!("0x{word_addr:08X}: 0x{value:08X}");
+            }
+            flasher.reset(false)?;
+        }
+        "write-mem" => {
+            let addr = parse_int(sub_args.value_of("ADDR").unwrap())?;
+            let value = parse_int(sub_args.value_of("VALUE").unwrap())?;
+            let mask = parse_int(sub_args.value_of("MASK").unwrap())?;
+            let delay = parse_int(sub_args.value_of("delay").unwrap())?;
+            let mut flasher = open_connection(&args)?;
+            flasher.write_mem(addr, value, mask, delay)?;
+            println!("Wrote 0x{value:08X} to 0x{addr:08X} with mask 0x{mask:08X}");
+            flasher.reset(false)?;
+        }
+        "dump-mem" => {
+            let addr = parse_int(sub_args.value_of("ADDR").unwrap())?;
+            let size = parse_int(sub_args.value_of("SIZE").unwrap())?;
+            let path = sub_args.value_of_os("OUTPUT_PATH").unwrap();
+            let mut flasher = open_connection(&args)?;
+            let data = flasher.dump_mem(addr, size)?;
+            flasher.reset(false)?;
+            println!("Read {size} bytes at 0x{addr:08X}:");
+            print!("{}", HexDump(&data));
+            std::fs::write(path, data).context("Unable to write memory dump")?;
+        }
+        "coredump" => {
+            let mut flasher = open_connection(&args)?;
+            let coredump = flasher.read_coredump()?;
+            flasher.reset(false)?;
+            println!("{coredump}");
+            for task in coredump.tasks()? {
+                print!("{task}");
+            }
+            if let Some(path) = sub_args.value_of_os("OUTPUT_PATH") {
+                std::fs::write(path, &coredump.elf).context("Unable to write ELF core file")?;
+            }
+        }
+        "load-ram" => {
+            let path = sub_args.value_of_os("PATH").unwrap();
+            let data = std::fs::read(path).context("Unable to read file")?;
+            let mut flasher = open_connection(&args)?;
+            let image = if data.starts_with(b"\x7FELF") {
+                elf_to_image(flasher.chip()?, &data)?
+            } else {
+                EspImage::try_from(data.as_slice())?
+            };
+            flasher.load_ram(&image)?;
+            println!("Running from 0x{:08X}", image.header.entry_addr);
+        }
+        "monitor" => {
+            let debug_info = match sub_args.value_of_os("elf") {
+                Some(path) => {
+                    let data = std::fs::read(path).context("Unable to read ELF file")?;
+                    Some(ElfDebugInfo::new(&data)?)
+                }
+                None => None,
+            };
+            monitor(
+                &args,
+                sub_args.is_present("timestamps"),
+                debug_info.as_ref(),
+            )?
+        }
         "image-info" => {
             let path = sub_args.value_of_os("IMAGE_PATH").unwrap();
             let image = std::fs::read(path).context("Unable to read image file")?;
@@ -203,12 +815,43 @@ fn main() -> Result<()> {
                 Cow::Borrowed,
             );
             let data = std::fs::read(elf_path)?;
-            let image = elf_to_image(chip, &data)?;
+            let mut image = elf_to_image(chip, &data)?;
+            let params = flash_params(sub_args)?;
+            if !params.is_empty() {
+                image.header.set_flash_params(chip, &params)?;
+                image.update_metadata();
+            }
             println!("Writing output to {image_path:#?}");
             let output = std::fs::File::create(image_path)?;
             let mut writer = std::io::BufWriter::new(output);
             image.write_to(&mut writer)?;
         }
+        "stub" => match sub_args.subcommand().unwrap() {
+            ("convert", convert_args) => {
+                let chip = convert_args
+                    .value_of("chip")
+                    .map(|chip| Chip::try_from(chip).unwrap())
+                    .context("The chip must be specified with --chip")?;
+                let input_path = convert_args.value_of_os("INPUT_PATH").unwrap();
+                let input = std::fs::read(input_path).context("Unable to read stub input")?;
+                let stub = if input.starts_with(b"\x7FELF") {
+                    Stub::from_elf(chip, &input)?
+                } else {
+                    let json = std::str::from_utf8(&input).context("Invalid JSON stub")?;
+                    Stub::from_json(chip, json)?
+                };
+                println!("{stub}");
+                let output_path = convert_args.value_of_os("OUTPUT_PATH").unwrap();
+                std::fs::write(output_path, stub.to_bytes()?)
+                    .context("Unable to write stub file")?;
+            }
+            ("info", info_args) => {
+                let path = info_args.value_of_os("STUB_PATH").unwrap();
+                let data = std::fs::read(path).context("Unable to read stub file")?;
+                println!("{}", Stub::from_bytes(&data)?);
+            }
+            _ => unreachable!(),
+        },
 
         _ => unreachable!(),
     }
@@ -18,6 +18,10 @@ use std::borrow::Cow;
 use std::fmt::{Display, Write};
 use std::io::Cursor;
 
+/// The default location and maximum size of the partition table in flash.
+pub const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+pub const PARTITION_TABLE_SIZE: u32 = 0xC00;
+
 pub const TYPE_APP: u8 = 0;
 pub const SUBTYPE_APP_FACTORY: u8 = 0;
 pub const SUBTYPE_APP_TEST: u8 = 0x20;
@@ -38,6 +42,23 @@ pub struct EspPartitionTable {
     pub entries: Vec<PartitionEntry>,
 }
 
+impl EspPartitionTable {
+    /// The offset and size of the first partition with the given type and
+    /// subtype.
+    pub fn find(&self, type_: u8, subtype: u8) -> Option<(u32, u32)> {
+        self.entries.iter().find_map(|entry| match *entry {
+            PartitionEntry::Partition {
+                type_: t,
+                subtype: s,
+                offset,
+                size,
+                ..
+            } if t == type_ && s == subtype => Some((offset, size)),
+            _ => None,
+        })
+    }
+}
+
 impl TryFrom<&[u8]> for EspPartitionTable {
     type Error = Error;
 
@@ -19,18 +19,20 @@ use std::rc::Rc;
 use std::time::{Duration, Instant};
 
 use binrw::{BinRead, BinWrite};
-use serialport::SerialPort;
 
 use crate::command::{Command, CommandError, ResponsePacket};
 use crate::event::{Event, EventObserver, EventProvider};
+use crate::reset::ResetStrategy;
+use crate::security::SecurityInfo;
 use crate::timeout::ErrorExt;
+use crate::transport::Transport;
 use crate::Error;
 use crate::Result;
 
 const DEFAULT_SERIAL_TIMEOUT: Duration = Duration::from_millis(10);
 
 struct TimeoutSerialPort {
-    inner: Box<dyn SerialPort>,
+    inner: Box<dyn Transport>,
     start: Instant,
     timeout: Duration,
     event_provider: EventProvider,
@@ -42,8 +44,7 @@ impl std::io::Read for TimeoutSerialPort {
             DEFAULT_SERIAL_TIMEOUT,
             self.timeout.saturating_sub(self.start.elapsed()),
         );
-        self.inner.set_timeout(timeout)?;
-        let size = self.inner.read(buf)?;
+        let size = self.inner.read(buf, timeout)?;
         self.event_provider
             .send_event(Event::SerialRead(Cow::from(&buf[..size])));
         Ok(size)
@@ -53,11 +54,14 @@ impl std::io::Read for TimeoutSerialPort {
 pub struct Protocol {
     serial: BufReader<TimeoutSerialPort>,
     is_rom_loader: bool,
+    reset_strategy: ResetStrategy,
     event_provider: EventProvider,
+    // The start of a line whose read timed out.
+    partial_line: Vec<u8>,
 }
 
 impl Protocol {
-    pub(crate) fn new(serial: Box<dyn SerialPort>) -> Self {
+    pub(crate) fn new(serial: Box<dyn Transport>) -> Self {
         let event_provider = EventProvider::new();
         let serial = TimeoutSerialPort {
             inner: serial,
@@ -69,7 +73,9 @@ impl Protocol {
         Protocol {
             serial: BufReader::new(serial),
             is_rom_loader: true,
-            event_provider: EventProvider::new(),
+            reset_strategy: ResetStrategy::default(),
+            event_provider,
+            partial_line: Vec::new(),
         }
     }
 
@@ -95,8 +101,25 @@ impl Protocol {
         self.is_rom_loader = is_rom_loader;
     }
 
+    pub fn baud_rate(&mut self) -> Result<u32> {
+        self.serial().baud_rate()
+    }
+
+    /// Set the speed of the serial port without telling the device.
+    pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<()> {
+        self.serial().set_baud_rate(baud_rate)
+    }
+
+    pub fn reset_strategy(&self) -> &ResetStrategy {
+        &self.reset_strategy
+    }
+
+    pub fn set_reset_strategy(&mut self, reset_strategy: ResetStrategy) {
+        self.reset_strategy = reset_strategy;
+    }
+
     #[inline]
-    fn serial(&mut self) -> &mut dyn SerialPort {
+    fn serial(&mut self) -> &mut dyn Transport {
         self.serial.get_mut().inner.as_mut()
     }
 
@@ -122,9 +145,7 @@ impl Protocol {
         self.trace(Event::SlipWrite(Cow::from(data)));
         self.trace(Event::SerialWrite(Cow::from(&output)));
 
-        let serial = self.serial();
-        serial.set_timeout(DEFAULT_SERIAL_TIMEOUT)?;
-        serial.write_all(&output)?;
+        self.serial().write_all(&output)?;
         Ok(())
     }
 
@@ -219,18 +240,30 @@ impl Protocol {
         }
     }
 
-    // Read a line of text.
-    // If a complete line has not been received by the timeout, then subsequent
-    // bytes must arrive within `DEFAULT_SERIAL_TIMEOUT`.
+    /// Read a line, including the newline. If the read times out, the part
+    /// of the line read so far is kept and returned by the next call.
     pub fn read_line(&mut self, timeout: Duration) -> Result<Vec<u8>> {
-        let mut line: Vec<u8> = Vec::new();
-
         self.set_timeout(timeout);
-        self.serial.read_until(b'\n', &mut line)?;
+        self.serial.read_until(b'\n', &mut self.partial_line)?;
+        let line = std::mem::take(&mut self.partial_line);
         self.trace(Event::SerialLine(Cow::from(&line)));
         Ok(line)
     }
 
+    /// The part of a line read so far by `read_line` calls that timed out.
+    pub fn partial_line(&self) -> &[u8] {
+        &self.partial_line
+    }
+
+    /// Write `data` to the serial port as is.
+    pub fn write_raw(&mut self, data: &[u8]) -> Result<()> {
+        self.trace(Event::SerialWrite(Cow::from(data)));
+        let serial = self.serial();
+        serial.write_all(data)?;
+        serial.flush()?;
+        Ok(())
+    }
+
     #[inline]
     fn trace(&mut self, event: Event) {
         self.event_provider.send_event(event);
@@ -273,25 +306,15 @@ impl Protocol {
     pub fn reset(&mut self, enter_bootloader: bool) -> Result<()> {
         self.trace(Event::Reset);
         self.serial.consume(self.serial.buffer().len());
+        self.partial_line.clear();
+        self.serial().clear()?;
 
+        if self.reset_strategy == ResetStrategy::NoReset {
+            return Ok(());
+        }
         self.is_rom_loader = true;
-
-        let serial = self.serial();
-        serial.clear(serialport::ClearBuffer::All)?;
-
-        // /RTS is connected to EN
-        // /DTR is connected to GPIO0
-        serial.write_request_to_send(true)?;
-        serial.write_data_terminal_ready(false)?;
-        std::thread::sleep(Duration::from_millis(100));
-        serial.clear(serialport::ClearBuffer::All)?;
-
-        serial.write_data_terminal_ready(enter_bootloader)?;
-        serial.write_request_to_send(false)?;
-        std::thread::sleep(Duration::from_millis(500));
-        serial.write_data_terminal_ready(false)?;
-
-        Ok(())
+        let strategy = self.reset_strategy.clone();
+        strategy.run(self.serial(), enter_bootloader)
     }
 
     pub fn flash_begin(
@@ -414,11 +437,23 @@ impl Protocol {
     }
 
     pub fn write_reg(&mut self, address: u32, value: u32) -> Result<()> {
+        self.write_reg_masked(address, value, 0xFFFFFFFF, 0)
+    }
+
+    /// Write the bits of `value` selected by `mask` to the register at
+    /// `address` and then wait `delay` microseconds.
+    pub fn write_reg_masked(
+        &mut self,
+        address: u32,
+        value: u32,
+        mask: u32,
+        delay: u32,
+    ) -> Result<()> {
         self.send_command(Command::WriteReg {
             address,
             value,
-            mask: 0xFFFFFFFF,
-            delay: 0,
+            mask,
+            delay,
         })?;
         Ok(())
     }
@@ -462,6 +497,64 @@ impl Protocol {
         Ok(())
     }
 
+    pub fn get_security_info(&mut self) -> Result<SecurityInfo> {
+        let (_value, data) = self.send_command(Command::GetSecurityInfo)?;
+        SecurityInfo::from_bytes(&data)
+    }
+
+    pub fn erase_flash(&mut self) -> Result<()> {
+        self.send_command(Command::EraseFlash)?;
+        Ok(())
+    }
+
+    pub fn erase_region(&mut self, flash_offset: u32, size: u32) -> Result<()> {
+        self.send_command(Command::EraseRegion { flash_offset, size })?;
+        Ok(())
+    }
+
+    /// Read `read_length` bytes of flash starting at `offset`. This is only
+    /// supported by the stub loader.
+    ///
+    /// The stub sends the data in packets of `packet_size` bytes and the host
+    /// acknowledges each packet by sending the total number of bytes received
+    /// so far. The stub stops sending once `max_pending_packets` are
+    /// unacknowledged. After all of the data, the stub sends the MD5 digest of
+    /// the data which is returned along with the data.
+    pub fn read_flash(
+        &mut self,
+        offset: u32,
+        read_length: u32,
+        packet_size: u32,
+        max_pending_packets: u32,
+    ) -> Result<(Vec<u8>, [u8; 16])> {
+        let cmd = Command::ReadFlash {
+            offset,
+            read_length,
+            packet_size,
+            max_pending_packets,
+        };
+        let timeout = cmd.timeout();
+        self.send_command(cmd)?;
+
+        let read_length = read_length as usize;
+        let mut data: Vec<u8> = Vec::with_capacity(read_length);
+        while data.len() < read_length {
+            let packet = self.read_packet(timeout)?;
+            if packet.is_empty() || data.len() + packet.len() > read_length {
+                return Err(CommandError::InvalidResponse.into());
+            }
+            data.extend(&packet);
+            let received = data.len() as u32;
+            self.send_packet(&received.to_le_bytes())?;
+        }
+
+        let digest: [u8; 16] = self
+            .read_packet(timeout)?
+            .try_into()
+            .map_err(|_| CommandError::InvalidResponse)?;
+        Ok((data, digest))
+    }
+
     pub fn spi_flash_md5(&mut self, address: u32, size: u32) -> Result<[u8; 16]> {
         let (_value, data) = self.send_command(Command::SpiFlashMD5 { address, size })?;
         let mut result = [0u8; 16];
@@ -0,0 +1,294 @@
+// Copyright 2022 Stephen Checkoway
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Strategies for resetting the device using the DTR and RTS lines.
+//!
+//! A reset sequence is written as steps separated by `|`:
+//!
+//! - `D0`/`D1` sets DTR low/high;
+//! - `R0`/`R1` sets RTS low/high;
+//! - `U0,1` sets DTR and RTS at the same time (here DTR low and RTS high),
+//!   if the transport supports it;
+//! - `W100` waits 100 milliseconds.
+//!
+//! On most development boards, RTS is connected to EN and DTR is connected to
+//! GPIO0, both inverted. For example, the classic sequence for entering the
+//! bootloader is `D0|R1|W100|D1|R0|W500|D0`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetStep {
+    Dtr(bool),
+    Rts(bool),
+    DtrRts(bool, bool),
+    Wait(Duration),
+}
+
+impl ResetStep {
+    fn run(&self, serial: &mut dyn Transport) -> Result<()> {
+        match *self {
+            ResetStep::Dtr(level) => serial.write_data_terminal_ready(level),
+            ResetStep::Rts(level) => serial.write_request_to_send(level),
+            ResetStep::DtrRts(dtr, rts) => serial.write_dtr_rts(dtr, rts),
+            ResetStep::Wait(duration) => {
+                std::thread::sleep(duration);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for ResetStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ResetStep::Dtr(level) => write!(f, "D{}", level as u8),
+            ResetStep::Rts(level) => write!(f, "R{}", level as u8),
+            ResetStep::DtrRts(dtr, rts) => write!(f, "U{},{}", dtr as u8, rts as u8),
+            ResetStep::Wait(duration) => write!(f, "W{}", duration.as_millis()),
+        }
+    }
+}
+
+impl FromStr for ResetStep {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let level = |value: &str| match value {
+            "0" => Some(false),
+            "1" => Some(true),
+            _ => None,
+        };
+        let mut chars = s.chars();
+        let kind = chars.next();
+        let value = chars.as_str();
+        let step = match kind {
+            Some('D') => level(value).map(ResetStep::Dtr),
+            Some('R') => level(value).map(ResetStep::Rts),
+            Some('U') => value
+                .split_once(',')
+                .and_then(|(dtr, rts)| Some(ResetStep::DtrRts(level(dtr)?, level(rts)?))),
+            Some('W') => value
+                .parse::<u64>()
+                .ok()
+                .map(|ms| ResetStep::Wait(Duration::from_millis(ms))),
+            _ => None,
+        };
+        step.ok_or_else(|| Error::FormatError(format!("Invalid reset step \"{s}\"")))
+    }
+}
+
+/// How to reset the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResetStrategy {
+    /// Toggle RTS (EN) and DTR (GPIO0) one at a time. This works with most
+    /// USB-to-UART bridges.
+    Classic,
+    /// The sequence needed by the USB-Serial-JTAG peripheral built into chips
+    /// such as the ESP32-C3 and ESP32-S3. Resetting into the application
+    /// only pulses RTS.
+    UsbJtagSerial,
+    /// Like `Classic`, but DTR and RTS are changed together to avoid
+    /// glitches on adapters where the lines interact. Only serial ports on
+    /// Unix can change both lines at once; other transports change DTR and
+    /// then RTS.
+    UnixTight,
+    /// Never touch DTR and RTS. The device must already be running the
+    /// loader.
+    NoReset,
+    /// A custom sequence used for entering the bootloader. Resetting into the
+    /// application pulses RTS.
+    Custom(Vec<ResetStep>),
+}
+
+impl Default for ResetStrategy {
+    fn default() -> Self {
+        ResetStrategy::Classic
+    }
+}
+
+impl ResetStrategy {
+    /// The sequence of steps that resets the device, entering the bootloader
+    /// if `enter_bootloader` is true.
+    pub fn steps(&self, enter_bootloader: bool) -> Vec<ResetStep> {
+        use ResetStep::*;
+        const fn ms(ms: u64) -> ResetStep {
+            Wait(Duration::from_millis(ms))
+        }
+        match (self, enter_bootloader) {
+            (ResetStrategy::NoReset, _) => Vec::new(),
+            (ResetStrategy::Classic, true) => vec![
+                Dtr(false),
+                Rts(true),
+                ms(100),
+                Dtr(true),
+                Rts(false),
+                ms(500),
+                Dtr(false),
+            ],
+            (ResetStrategy::UsbJtagSerial, true) => vec![
+                Rts(false),
+                Dtr(false),
+                ms(100),
+                Dtr(true),
+                Rts(false),
+                ms(100),
+                Rts(true),
+                Dtr(false),
+                Rts(true),
+                ms(100),
+                Rts(false),
+                Dtr(false),
+            ],
+            (ResetStrategy::UnixTight, true) => vec![
+                DtrRts(false, false),
+                DtrRts(true, true),
+                DtrRts(false, true),
+                ms(100),
+                DtrRts(true, false),
+                ms(500),
+                DtrRts(false, false),
+                Dtr(false),
+            ],
+            (ResetStrategy::Custom(steps), true) => steps.clone(),
+            // Only pulse EN, giving the chip time to come out of reset before
+            // it can handle further line changes.
+            (ResetStrategy::UsbJtagSerial, false) => vec![Rts(true), ms(200), Rts(false), ms(200)],
+            (_, false) => vec![Dtr(false), Rts(true), ms(100), Rts(false), ms(500)],
+        }
+    }
+
+    pub(crate) fn run(&self, serial: &mut dyn Transport, enter_bootloader: bool) -> Result<()> {
+        for step in self.steps(enter_bootloader) {
+            step.run(serial)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ResetStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetStrategy::Classic => f.write_str("classic"),
+            ResetStrategy::UsbJtagSerial => f.write_str("usb-jtag-serial"),
+            ResetStrategy::UnixTight => f.write_str("unix-tight"),
+            ResetStrategy::NoReset => f.write_str("no-reset"),
+            ResetStrategy::Custom(steps) => {
+                for (idx, step) in steps.iter().enumerate() {
+                    if idx != 0 {
+                        f.write_str("|")?;
+                    }
+                    write!(f, "{step}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for ResetStrategy {
+    type Err = Error;
+
+    /// Parse the name of a built-in strategy or a custom sequence.
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "classic" => ResetStrategy::Classic,
+            "usb-jtag-serial" => ResetStrategy::UsbJtagSerial,
+            "unix-tight" => ResetStrategy::UnixTight,
+            "no-reset" => ResetStrategy::NoReset,
+            _ => ResetStrategy::Custom(
+                s.split('|')
+                    .map(|step| step.trim().parse())
+                    .collect::<Result<_>>()?,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::test_util::connect;
+    use crate::sim::SimulatedDevice;
+    use crate::{Chip, Flasher};
+
+    #[test]
+    fn test_parse_reset_strategy() -> Result<()> {
+        let classic = ResetStrategy::Classic.steps(true);
+        let custom: ResetStrategy = "D0|R1|W100|D1|R0|W500|D0".parse()?;
+        assert_eq!(custom, ResetStrategy::Custom(classic));
+        assert_eq!(custom.to_string(), "D0|R1|W100|D1|R0|W500|D0");
+
+        let strategy: ResetStrategy = "U0,1| W50".parse()?;
+        assert_eq!(
+            strategy.steps(true),
+            [
+                ResetStep::DtrRts(false, true),
+                ResetStep::Wait(Duration::from_millis(50))
+            ]
+        );
+        assert_eq!(
+            "usb-jtag-serial".parse::<ResetStrategy>()?,
+            ResetStrategy::UsbJtagSerial
+        );
+        assert!("D2".parse::<ResetStrategy>().is_err());
+        assert!("W".parse::<ResetStrategy>().is_err());
+        assert!("D0||R1".parse::<ResetStrategy>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_strategies() -> Result<()> {
+        let device = SimulatedDevice::new(Chip::Esp32C3);
+        let mut flasher = Flasher::with_transport(device.clone());
+        flasher.set_reset_strategy("D0|R1|W10|D1|R0|W10|D0".parse()?);
+        assert_eq!(flasher.connect()?, Chip::Esp32C3);
+
+        // Without a reset, the device stays in the loader.
+        flasher.set_reset_strategy(ResetStrategy::NoReset);
+        flasher.reset(false)?;
+        assert!(!device.is_app_running());
+        assert_eq!(flasher.connect()?, Chip::Esp32C3);
+
+        flasher.set_reset_strategy(ResetStrategy::UnixTight);
+        flasher.reset(false)?;
+        assert!(device.is_app_running());
+
+        // The simulated device has the classic auto-reset circuit rather than
+        // a USB-Serial-JTAG peripheral, but pulsing RTS still resets it.
+        flasher.set_reset_strategy(ResetStrategy::Classic);
+        assert_eq!(flasher.connect()?, Chip::Esp32C3);
+        flasher.set_reset_strategy(ResetStrategy::UsbJtagSerial);
+        flasher.reset(false)?;
+        assert!(device.is_app_running());
+        assert!(ResetStrategy::UsbJtagSerial
+            .steps(false)
+            .iter()
+            .all(|step| matches!(step, ResetStep::Rts(_) | ResetStep::Wait(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_to_app() -> Result<()> {
+        let (device, mut flasher) = connect(Chip::Esp32, false)?;
+        flasher.reset(false)?;
+        assert!(device.is_app_running());
+        Ok(())
+    }
+}
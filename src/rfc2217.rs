@@ -0,0 +1,438 @@
+// Copyright 2022 Stephen Checkoway
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A [`Transport`] for serial ports exposed over the network using the telnet
+//! COM port control option described in
+//! [RFC 2217](https://datatracker.ietf.org/doc/html/rfc2217), e.g., by
+//! `ser2net`.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::transport::{read_stream, Transport};
+use crate::Result;
+
+// Telnet commands.
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+// Telnet options.
+const BINARY: u8 = 0;
+const SGA: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+// COM port option commands sent by the client. The server's responses are
+// the same values plus 100.
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const PURGE_DATA: u8 = 12;
+const SERVER_OFFSET: u8 = 100;
+
+// SET_CONTROL values.
+const DTR_ON: u8 = 8;
+const DTR_OFF: u8 = 9;
+const RTS_ON: u8 = 11;
+const RTS_OFF: u8 = 12;
+
+// PURGE_DATA value to purge both the receive and transmit buffers.
+const PURGE_BOTH: u8 = 3;
+
+const PARITY_NONE: u8 = 1;
+const STOPSIZE_1: u8 = 1;
+
+// How long to wait for the server to acknowledge a baud rate change.
+const BAUD_RATE_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Eq)]
+enum TelnetEvent {
+    Data(u8),
+    Negotiation(u8, u8),
+    Subnegotiation(Vec<u8>),
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+// Splits a telnet byte stream into data and commands.
+struct TelnetDecoder {
+    state: State,
+    subnegotiation: Vec<u8>,
+}
+
+impl TelnetDecoder {
+    fn new() -> Self {
+        TelnetDecoder {
+            state: State::Data,
+            subnegotiation: Vec::new(),
+        }
+    }
+
+    fn decode(&mut self, byte: u8) -> Option<TelnetEvent> {
+        let (state, event) = match (self.state, byte) {
+            (State::Data, IAC) => (State::Iac, None),
+            (State::Data, _) => (State::Data, Some(TelnetEvent::Data(byte))),
+            (State::Iac, IAC) => (State::Data, Some(TelnetEvent::Data(IAC))),
+            (State::Iac, DO | DONT | WILL | WONT) => (State::Negotiation(byte), None),
+            (State::Iac, SB) => {
+                self.subnegotiation.clear();
+                (State::Subnegotiation, None)
+            }
+            // Other commands have no arguments and are ignored.
+            (State::Iac, _) => (State::Data, None),
+            (State::Negotiation(cmd), _) => {
+                (State::Data, Some(TelnetEvent::Negotiation(cmd, byte)))
+            }
+            (State::Subnegotiation, IAC) => (State::SubnegotiationIac, None),
+            (State::Subnegotiation, _) => {
+                self.subnegotiation.push(byte);
+                (State::Subnegotiation, None)
+            }
+            (State::SubnegotiationIac, SE) => (
+                State::Data,
+                Some(TelnetEvent::Subnegotiation(std::mem::take(
+                    &mut self.subnegotiation,
+                ))),
+            ),
+            (State::SubnegotiationIac, _) => {
+                self.subnegotiation.push(byte);
+                (State::Subnegotiation, None)
+            }
+        };
+        self.state = state;
+        event
+    }
+}
+
+// Escape IAC bytes in `data` by doubling them.
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() + 8);
+    for &byte in data {
+        output.push(byte);
+        if byte == IAC {
+            output.push(IAC);
+        }
+    }
+    output
+}
+
+/// A serial port accessed via an RFC 2217 server.
+pub struct Rfc2217Transport {
+    stream: TcpStream,
+    decoder: TelnetDecoder,
+    data: VecDeque<u8>,
+    baud_rate: u32,
+    acked_baud_rate: Option<u32>,
+}
+
+impl Rfc2217Transport {
+    /// Connect to the RFC 2217 server at `addr` and configure the serial port
+    /// for 115200 baud, 8N1.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut transport = Rfc2217Transport {
+            stream,
+            decoder: TelnetDecoder::new(),
+            data: VecDeque::new(),
+            baud_rate: 115200,
+            acked_baud_rate: None,
+        };
+        transport.send_raw(&[
+            IAC,
+            WILL,
+            BINARY,
+            IAC,
+            DO,
+            BINARY,
+            IAC,
+            WILL,
+            SGA,
+            IAC,
+            DO,
+            SGA,
+            IAC,
+            WILL,
+            COM_PORT_OPTION,
+        ])?;
+        transport.set_baud_rate(115200)?;
+        transport.send_com_port_command(SET_DATASIZE, &[8])?;
+        transport.send_com_port_command(SET_PARITY, &[PARITY_NONE])?;
+        transport.send_com_port_command(SET_STOPSIZE, &[STOPSIZE_1])?;
+        Ok(transport)
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data)
+    }
+
+    fn send_com_port_command(&mut self, command: u8, value: &[u8]) -> io::Result<()> {
+        let mut packet = vec![IAC, SB, COM_PORT_OPTION, command];
+        packet.extend(escape(value));
+        packet.extend([IAC, SE]);
+        self.send_raw(&packet)
+    }
+
+    fn handle_event(&mut self, event: TelnetEvent) -> io::Result<()> {
+        match event {
+            TelnetEvent::Data(byte) => self.data.push_back(byte),
+            TelnetEvent::Negotiation(cmd, option) => {
+                // Refuse any option we didn't ask for.
+                if !matches!(option, BINARY | SGA | COM_PORT_OPTION) {
+                    match cmd {
+                        DO => self.send_raw(&[IAC, WONT, option])?,
+                        WILL => self.send_raw(&[IAC, DONT, option])?,
+                        _ => (),
+                    }
+                }
+            }
+            TelnetEvent::Subnegotiation(data) => {
+                if data.len() == 6
+                    && data[0] == COM_PORT_OPTION
+                    && data[1] == SET_BAUDRATE + SERVER_OFFSET
+                {
+                    let rate = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+                    self.acked_baud_rate = Some(rate);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Read from the socket once, waiting until at most `deadline`, and
+    // process the telnet stream.
+    fn receive(&mut self, deadline: Instant) -> io::Result<()> {
+        let mut buf = [0u8; 1024];
+        let timeout = deadline
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_millis(1));
+        self.stream.set_read_timeout(Some(timeout))?;
+        let size = read_stream(&mut self.stream, &mut buf)?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        for &byte in &buf[..size] {
+            if let Some(event) = self.decoder.decode(byte) {
+                self.handle_event(event)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Rfc2217Transport {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let deadline = Instant::now() + timeout;
+        while self.data.is_empty() {
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.receive(deadline)?;
+        }
+        let size = buf.len().min(self.data.len());
+        for (dest, src) in buf.iter_mut().zip(self.data.drain(..size)) {
+            *dest = src;
+        }
+        Ok(size)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_raw(&escape(data))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+
+    fn baud_rate(&self) -> Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<()> {
+        self.acked_baud_rate = None;
+        self.send_com_port_command(SET_BAUDRATE, &baud_rate.to_be_bytes())?;
+
+        // Wait for the server to acknowledge the new rate. Any data that
+        // arrives in the meantime is buffered.
+        let deadline = Instant::now() + BAUD_RATE_ACK_TIMEOUT;
+        while self.acked_baud_rate.is_none() && Instant::now() < deadline {
+            match self.receive(deadline) {
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => break,
+                result => result?,
+            }
+        }
+        let message = match self.acked_baud_rate {
+            Some(rate) if rate == baud_rate => {
+                self.baud_rate = baud_rate;
+                return Ok(());
+            }
+            Some(rate) => {
+                format!("RFC 2217 server set baud rate {rate} instead of {baud_rate}")
+            }
+            None => format!("RFC 2217 server did not acknowledge baud rate {baud_rate}"),
+        };
+        Err(io::Error::new(io::ErrorKind::Other, message).into())
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> Result<()> {
+        let value = if level { DTR_ON } else { DTR_OFF };
+        Ok(self.send_com_port_command(SET_CONTROL, &[value])?)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> Result<()> {
+        let value = if level { RTS_ON } else { RTS_OFF };
+        Ok(self.send_com_port_command(SET_CONTROL, &[value])?)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.data.clear();
+        Ok(self.send_com_port_command(PURGE_DATA, &[PURGE_BOTH])?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    // A stand-in RFC 2217 server which acknowledges COM port commands,
+    // clamping baud rates to `max_baud_rate`, echoes data back to the client,
+    // and returns everything it received once the client disconnects.
+    fn serve(listener: TcpListener, max_baud_rate: u32) -> Vec<TelnetEvent> {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut decoder = TelnetDecoder::new();
+        let mut events = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let size = stream.read(&mut buf).unwrap();
+            if size == 0 {
+                return events;
+            }
+            for &byte in &buf[..size] {
+                match decoder.decode(byte) {
+                    Some(TelnetEvent::Data(byte)) => {
+                        stream.write_all(&escape(&[byte])).unwrap();
+                        events.push(TelnetEvent::Data(byte));
+                    }
+                    Some(TelnetEvent::Subnegotiation(mut data)) => {
+                        let mut response = vec![IAC, SB];
+                        data[1] += SERVER_OFFSET;
+                        let mut ack = data.clone();
+                        if data[1] == SET_BAUDRATE + SERVER_OFFSET {
+                            let rate = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+                            ack[2..6].copy_from_slice(&rate.min(max_baud_rate).to_be_bytes());
+                        }
+                        response.extend(escape(&ack));
+                        response.extend([IAC, SE]);
+                        stream.write_all(&response).unwrap();
+                        data[1] -= SERVER_OFFSET;
+                        events.push(TelnetEvent::Subnegotiation(data));
+                    }
+                    Some(event) => events.push(event),
+                    None => (),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rfc2217() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = thread::spawn(move || serve(listener, 921600));
+
+        let mut transport = Rfc2217Transport::connect(addr)?;
+        transport.set_baud_rate(921600)?;
+        assert_eq!(transport.baud_rate()?, 921600);
+        transport.write_data_terminal_ready(true)?;
+        transport.write_request_to_send(false)?;
+
+        let data = b"\xC0\xFF\x00\xFF\xFFdata\xC0";
+        transport.write_all(data)?;
+        let mut received: Vec<u8> = Vec::new();
+        let mut buf = [0u8; 16];
+        while received.len() < data.len() {
+            let size = transport.read(&mut buf, Duration::from_secs(5))?;
+            received.extend(&buf[..size]);
+        }
+        assert_eq!(received, data.as_slice());
+        let err = transport.read(&mut buf, Duration::from_millis(10));
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        drop(transport);
+
+        let events = server.join().unwrap();
+        let subnegotiations: Vec<&[u8]> = events
+            .iter()
+            .filter_map(|event| match event {
+                TelnetEvent::Subnegotiation(data) => Some(data.as_slice()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            subnegotiations,
+            [
+                &[COM_PORT_OPTION, SET_BAUDRATE, 0x00, 0x01, 0xC2, 0x00][..],
+                &[COM_PORT_OPTION, SET_DATASIZE, 8],
+                &[COM_PORT_OPTION, SET_PARITY, PARITY_NONE],
+                &[COM_PORT_OPTION, SET_STOPSIZE, STOPSIZE_1],
+                &[COM_PORT_OPTION, SET_BAUDRATE, 0x00, 0x0E, 0x10, 0x00],
+                &[COM_PORT_OPTION, SET_CONTROL, DTR_ON],
+                &[COM_PORT_OPTION, SET_CONTROL, RTS_OFF],
+            ]
+        );
+        let echoed: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event {
+                TelnetEvent::Data(byte) => Some(*byte),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(echoed, data.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rfc2217_clamped_baud_rate() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = thread::spawn(move || serve(listener, 460800));
+
+        let mut transport = Rfc2217Transport::connect(addr)?;
+        assert!(transport.set_baud_rate(921600).is_err());
+        assert_eq!(transport.baud_rate()?, 115200);
+        transport.set_baud_rate(460800)?;
+        assert_eq!(transport.baud_rate()?, 460800);
+        drop(transport);
+        server.join().unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2022 Stephen Checkoway
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The response to the `GET_SECURITY_INFO` command supported by the ESP32-S2
+//! and later chips.
+
+use std::fmt;
+
+use crate::command::CommandError;
+use crate::{from_le, Chip, Result};
+
+// https://github.com/espressif/esptool/blob/master/esptool/loader.py
+pub const FLAG_SECURE_BOOT_EN: u32 = 1 << 0;
+pub const FLAG_SECURE_BOOT_AGGRESSIVE_REVOKE: u32 = 1 << 1;
+pub const FLAG_SECURE_DOWNLOAD_ENABLE: u32 = 1 << 2;
+pub const FLAG_SECURE_BOOT_KEY_REVOKE0: u32 = 1 << 3;
+pub const FLAG_SECURE_BOOT_KEY_REVOKE1: u32 = 1 << 4;
+pub const FLAG_SECURE_BOOT_KEY_REVOKE2: u32 = 1 << 5;
+pub const FLAG_SOFT_DIS_JTAG: u32 = 1 << 6;
+pub const FLAG_HARD_DIS_JTAG: u32 = 1 << 7;
+pub const FLAG_DIS_USB: u32 = 1 << 8;
+pub const FLAG_DIS_DOWNLOAD_DCACHE: u32 = 1 << 9;
+pub const FLAG_DIS_DOWNLOAD_ICACHE: u32 = 1 << 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityInfo {
+    pub flags: u32,
+    pub flash_crypt_cnt: u8,
+    pub key_purposes: [u8; 7],
+    /// The chip ID and ECO version are only reported by the ESP32-C3 and
+    /// later chips.
+    pub chip_id: Option<u32>,
+    pub eco_version: Option<u32>,
+}
+
+impl SecurityInfo {
+    /// Parse the data from a `GET_SECURITY_INFO` response. The data is either
+    /// 12 bytes or, on chips that report the chip ID, 20 bytes.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() != 12 && data.len() != 20 {
+            return Err(CommandError::InvalidResponse.into());
+        }
+        let mut key_purposes = [0u8; 7];
+        key_purposes.copy_from_slice(&data[5..12]);
+        let (chip_id, eco_version) = if data.len() == 20 {
+            (Some(from_le(&data[12..16])), Some(from_le(&data[16..20])))
+        } else {
+            (None, None)
+        };
+        Ok(SecurityInfo {
+            flags: from_le(&data[0..4]),
+            flash_crypt_cnt: data[4],
+            key_purposes,
+            chip_id,
+            eco_version,
+        })
+    }
+
+    #[inline]
+    fn flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn secure_boot_enabled(&self) -> bool {
+        self.flag(FLAG_SECURE_BOOT_EN)
+    }
+
+    /// Flash encryption is enabled when an odd number of bits of the
+    /// `SPI_BOOT_CRYPT_CNT` eFuse are set.
+    pub fn flash_encryption_enabled(&self) -> bool {
+        self.flash_crypt_cnt.count_ones() % 2 == 1
+    }
+
+    pub fn jtag_disabled(&self) -> bool {
+        self.flag(FLAG_SOFT_DIS_JTAG | FLAG_HARD_DIS_JTAG)
+    }
+
+    pub fn secure_download_enabled(&self) -> bool {
+        self.flag(FLAG_SECURE_DOWNLOAD_ENABLE)
+    }
+
+    pub fn chip(&self) -> Option<Chip> {
+        self.chip_id
+            .and_then(|id| u16::try_from(id).ok())
+            .and_then(Chip::try_from_image_chip_id)
+    }
+}
+
+pub fn key_purpose_name(purpose: u8) -> &'static str {
+    match purpose {
+        0 => "USER",
+        1 => "RESERVED",
+        2 => "XTS_AES_256_KEY_1",
+        3 => "XTS_AES_256_KEY_2",
+        4 => "XTS_AES_128_KEY",
+        5 => "HMAC_DOWN_ALL",
+        6 => "HMAC_DOWN_JTAG",
+        7 => "HMAC_DOWN_DIGITAL_SIGNATURE",
+        8 => "HMAC_UP",
+        9 => "SECURE_BOOT_DIGEST0",
+        10 => "SECURE_BOOT_DIGEST1",
+        11 => "SECURE_BOOT_DIGEST2",
+        12 => "KM_INIT_KEY",
+        _ => "UNKNOWN",
+    }
+}
+
+impl fmt::Display for SecurityInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |flag: u32| if self.flag(flag) { "yes" } else { "no" };
+        let enabled = |enabled: bool| if enabled { "enabled" } else { "disabled" };
+
+        writeln!(f, "Flags: 0x{:08X}", self.flags)?;
+        writeln!(f, "Secure boot: {}", enabled(self.secure_boot_enabled()))?;
+        writeln!(
+            f,
+            "Secure boot aggressive revoke: {}",
+            yes_no(FLAG_SECURE_BOOT_AGGRESSIVE_REVOKE)
+        )?;
+        for (idx, flag) in [
+            FLAG_SECURE_BOOT_KEY_REVOKE0,
+            FLAG_SECURE_BOOT_KEY_REVOKE1,
+            FLAG_SECURE_BOOT_KEY_REVOKE2,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            writeln!(f, "Secure boot key {idx} revoked: {}", yes_no(flag))?;
+        }
+        writeln!(
+            f,
+            "Flash encryption: {} (SPI_BOOT_CRYPT_CNT 0x{:02X})",
+            enabled(self.flash_encryption_enabled()),
+            self.flash_crypt_cnt
+        )?;
+        let jtag = if self.flag(FLAG_HARD_DIS_JTAG) {
+            "hard disabled"
+        } else if self.flag(FLAG_SOFT_DIS_JTAG) {
+            "soft disabled"
+        } else {
+            "enabled"
+        };
+        writeln!(f, "JTAG: {jtag}")?;
+        writeln!(f, "USB: {}", enabled(!self.flag(FLAG_DIS_USB)))?;
+        writeln!(
+            f,
+            "Secure download mode: {}",
+            enabled(self.secure_download_enabled())
+        )?;
+        writeln!(
+            f,
+            "Download mode data cache: {}",
+            enabled(!self.flag(FLAG_DIS_DOWNLOAD_DCACHE))
+        )?;
+        writeln!(
+            f,
+            "Download mode instruction cache: {}",
+            enabled(!self.flag(FLAG_DIS_DOWNLOAD_ICACHE))
+        )?;
+        writeln!(f, "Key purposes:")?;
+        for (idx, &purpose) in self.key_purposes.iter().enumerate() {
+            writeln!(
+                f,
+                "  BLOCK_KEY{idx}: {} ({purpose})",
+                key_purpose_name(purpose)
+            )?;
+        }
+        if let Some(chip_id) = self.chip_id {
+            write!(f, "Chip ID: {chip_id}")?;
+            if let Some(chip) = self.chip() {
+                write!(f, " ({chip})")?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(eco_version) = self.eco_version {
+            writeln!(f, "ECO version: {eco_version}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_security_info() -> Result<()> {
+        let mut data = vec![0xC1, 0x00, 0x00, 0x00, 0x07];
+        data.extend([4, 9, 0, 0, 0, 0, 0]);
+        let info = SecurityInfo::from_bytes(&data)?;
+        assert!(info.secure_boot_enabled());
+        assert!(info.flash_encryption_enabled());
+        assert!(info.jtag_disabled());
+        assert!(!info.secure_download_enabled());
+        assert_eq!(info.chip(), None);
+
+        data[4] = 0x03;
+        data.extend(13u32.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        let info = SecurityInfo::from_bytes(&data)?;
+        assert!(!info.flash_encryption_enabled());
+        assert_eq!(info.chip(), Some(Chip::Esp32C6));
+        assert_eq!(info.eco_version, Some(1));
+
+        assert!(SecurityInfo::from_bytes(&data[..16]).is_err());
+        Ok(())
+    }
+}
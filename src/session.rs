@@ -0,0 +1,435 @@
+// Copyright 2022 Stephen Checkoway
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording and replaying serial sessions.
+//!
+//! A session is the sequence of resets, bytes written to the device, and
+//! bytes read from the device. Sessions are stored as text, one entry per
+//! line, with the time in seconds since the start of the session, the kind of
+//! entry, and the data in hex.
+//!
+//! ```text
+//! # espflashtool session
+//! 0.000000 reset
+//! 0.612043 read 7761697469...
+//! 0.612101 write C000082400...
+//! ```
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::event::{Event, EventObserver};
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const SESSION_HEADER: &str = "# espflashtool session";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEntry {
+    Reset,
+    Write(Vec<u8>),
+    Read(Vec<u8>),
+}
+
+impl SessionEntry {
+    fn from_event(event: &Event<'_>) -> Option<Self> {
+        match event {
+            Event::Reset => Some(SessionEntry::Reset),
+            Event::SerialWrite(data) => Some(SessionEntry::Write(data.to_vec())),
+            Event::SerialRead(data) => Some(SessionEntry::Read(data.to_vec())),
+            _ => None,
+        }
+    }
+}
+
+fn format_entry(f: &mut impl fmt::Write, time: Duration, entry: &SessionEntry) -> fmt::Result {
+    write!(f, "{}.{:06} ", time.as_secs(), time.subsec_micros())?;
+    let data = match entry {
+        SessionEntry::Reset => return writeln!(f, "reset"),
+        SessionEntry::Write(data) => {
+            f.write_str("write ")?;
+            data
+        }
+        SessionEntry::Read(data) => {
+            f.write_str("read ")?;
+            data
+        }
+    };
+    for &b in data {
+        write!(f, "{:02X}", b)?;
+    }
+    writeln!(f)
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+fn parse_entry(line: &str) -> Option<(Duration, SessionEntry)> {
+    let mut fields = line.split_whitespace();
+    let time = fields.next()?.parse::<f64>().ok()?;
+    if !time.is_finite() || time < 0.0 {
+        return None;
+    }
+    let entry = match (fields.next()?, fields.next()) {
+        ("reset", None) => SessionEntry::Reset,
+        ("write", data) => SessionEntry::Write(parse_hex(data.unwrap_or(""))?),
+        ("read", data) => SessionEntry::Read(parse_hex(data.unwrap_or(""))?),
+        _ => return None,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((Duration::from_secs_f64(time), entry))
+}
+
+/// A recorded serial session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+    entries: Vec<(Duration, SessionEntry)>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Construct a session from the `Reset`, `SerialWrite`, and `SerialRead`
+    /// events, e.g., those gathered by an
+    /// [`EventCollector`](crate::event::EventCollector). Other events are
+    /// ignored.
+    pub fn from_events(events: &[(Instant, Event<'_>)]) -> Self {
+        let start = match events.first() {
+            Some(&(start, _)) => start,
+            None => return Self::new(),
+        };
+        let entries = events
+            .iter()
+            .filter_map(|(timestamp, event)| {
+                SessionEntry::from_event(event).map(|entry| (*timestamp - start, entry))
+            })
+            .collect();
+        Session { entries }
+    }
+
+    pub fn entries(&self) -> &[(Duration, SessionEntry)] {
+        &self.entries
+    }
+
+    pub fn push(&mut self, time: Duration, entry: SessionEntry) {
+        self.entries.push((time, entry));
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{SESSION_HEADER}")?;
+        for (time, entry) in &self.entries {
+            format_entry(f, *time, entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Session {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut session = Session::new();
+        for (line_num, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (time, entry) = parse_entry(line).ok_or_else(|| {
+                Error::FormatError(format!("Invalid session entry on line {}", line_num + 1))
+            })?;
+            session.push(time, entry);
+        }
+        Ok(session)
+    }
+}
+
+pub struct SessionRecorderObserver<W> {
+    writer: Cell<Option<W>>,
+    start: Cell<Option<Instant>>,
+}
+
+/// Writes the session to a writer as the events occur so that the session
+/// is recorded even if the program exits with an error.
+pub struct SessionRecorder<W> {
+    observer: Rc<SessionRecorderObserver<W>>,
+}
+
+impl<W: io::Write> SessionRecorder<W> {
+    pub fn new(writer: W) -> Self {
+        SessionRecorder {
+            observer: Rc::new(SessionRecorderObserver {
+                writer: Cell::new(Some(writer)),
+                start: Cell::new(None),
+            }),
+        }
+    }
+
+    pub fn observer(&self) -> Rc<SessionRecorderObserver<W>> {
+        Rc::clone(&self.observer)
+    }
+}
+
+impl<W: io::Write + 'static> From<SessionRecorder<W>> for Rc<dyn EventObserver> {
+    fn from(recorder: SessionRecorder<W>) -> Self {
+        recorder.observer
+    }
+}
+
+impl<W: io::Write> EventObserver for SessionRecorderObserver<W> {
+    fn notify(&self, timestamp: Instant, event: &Event<'_>) {
+        let entry = match SessionEntry::from_event(event) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let mut line = String::new();
+        let start = match self.start.get() {
+            Some(start) => start,
+            None => {
+                self.start.set(Some(timestamp));
+                writeln!(line, "{SESSION_HEADER}").unwrap();
+                timestamp
+            }
+        };
+        if let Some(mut writer) = self.writer.take() {
+            format_entry(&mut line, timestamp - start, &entry).unwrap();
+            // Stop recording after the first error.
+            if writer.write_all(line.as_bytes()).is_ok() {
+                self.writer.set(Some(writer));
+            }
+        }
+    }
+}
+
+/// A transport which replays a recorded session.
+///
+/// Reads return the data the device sent in the session. Writes are compared
+/// against the data the host wrote in the session and fail with an error of
+/// kind `io::ErrorKind::InvalidData` if they differ. Resetting the device
+/// (which clears the transport) consumes a reset entry. When the next entry
+/// is not data from the device, reads time out.
+pub struct ReplayTransport {
+    entries: VecDeque<SessionEntry>,
+    // Number of bytes of the front entry which have been consumed.
+    offset: usize,
+    baud_rate: u32,
+}
+
+impl ReplayTransport {
+    pub fn new(session: &Session) -> Self {
+        ReplayTransport {
+            entries: session
+                .entries()
+                .iter()
+                .map(|(_, entry)| entry.clone())
+                .collect(),
+            offset: 0,
+            baud_rate: 115200,
+        }
+    }
+
+    /// Returns true if every entry in the session has been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn advance(&mut self, size: usize, entry_len: usize) {
+        self.offset += size;
+        if self.offset == entry_len {
+            self.entries.pop_front();
+            self.offset = 0;
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+        let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "Operation timed out");
+        let data = match self.entries.front() {
+            Some(SessionEntry::Read(data)) => &data[self.offset..],
+            _ => return Err(timed_out()),
+        };
+        if data.is_empty() {
+            // An empty read is a read that timed out.
+            self.advance(0, 0);
+            return Err(timed_out());
+        }
+        let size = buf.len().min(data.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        let entry_len = self.offset + data.len();
+        self.advance(size, entry_len);
+        Ok(size)
+    }
+
+    fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let expected = match self.entries.front() {
+                Some(SessionEntry::Write(expected)) => &expected[self.offset..],
+                entry => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Replay expected {entry:02X?} but the host wrote {data:02X?}"),
+                    ))
+                }
+            };
+            let size = data.len().min(expected.len());
+            if data[..size] != expected[..size] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Replay expected a write of {:02X?} but the host wrote {:02X?}",
+                        &expected[..size],
+                        &data[..size]
+                    ),
+                ));
+            }
+            let entry_len = self.offset + expected.len();
+            self.advance(size, entry_len);
+            data = &data[size..];
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn baud_rate(&self) -> Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        if self.entries.front() == Some(&SessionEntry::Reset) {
+            self.entries.pop_front();
+            self.offset = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::EventCollector;
+    use crate::sim::SimulatedDevice;
+    use crate::{Chip, Flasher};
+
+    fn record(chip: Chip) -> Result<(Session, (u8, u16))> {
+        let collector = EventCollector::new();
+        let mut flasher = Flasher::with_transport(SimulatedDevice::new(chip));
+        flasher.add_observer(collector.observer() as Rc<dyn EventObserver>);
+        flasher.connect()?;
+        let flash_id = flasher.flash_id()?;
+        drop(flasher);
+        Ok((Session::from_events(&collector.collect()), flash_id))
+    }
+
+    #[test]
+    fn test_record_replay() -> Result<()> {
+        let (session, flash_id) = record(Chip::Esp32)?;
+        assert_eq!(session.entries()[0].1, SessionEntry::Reset);
+
+        let session: Session = session.to_string().parse()?;
+        let mut flasher = Flasher::with_transport(ReplayTransport::new(&session));
+        assert_eq!(flasher.connect()?, Chip::Esp32);
+        assert_eq!(flasher.flash_id()?, flash_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_mismatch() -> Result<()> {
+        let (session, _) = record(Chip::Esp32C3)?;
+        let mut flasher = Flasher::with_transport(ReplayTransport::new(&session));
+        flasher.connect()?;
+        // The session read the flash ID rather than erasing.
+        let err = flasher.erase_region(0, 0x1000).unwrap_err();
+        assert!(matches!(err, Error::IOError(err) if err.kind() == io::ErrorKind::InvalidData));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_session() {
+        let session: Session = "# comment\n0.5 reset\n0.500100 write C0DB\n1.25 read \n"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            session.entries(),
+            [
+                (Duration::from_millis(500), SessionEntry::Reset),
+                (
+                    Duration::from_micros(500_100),
+                    SessionEntry::Write(vec![0xC0, 0xDB])
+                ),
+                (Duration::from_millis(1250), SessionEntry::Read(vec![])),
+            ]
+        );
+        assert!("0.5 write C0D".parse::<Session>().is_err());
+        assert!("reset".parse::<Session>().is_err());
+    }
+
+    #[test]
+    fn test_replay_empty_read() -> Result<()> {
+        let session: Session = "0 read \n0 read 41\n".parse()?;
+        let mut transport = ReplayTransport::new(&session);
+        let mut buf = [0u8; 4];
+        let err = transport.read(&mut buf, Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(transport.read(&mut buf, Duration::ZERO)?, 1);
+        assert_eq!(buf[0], 0x41);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_line() -> Result<()> {
+        let session: Session = "0 reset\n0 read 68656C\n0 write 78\n0 read 6C6F0D0A\n".parse()?;
+        let mut flasher = Flasher::with_transport(ReplayTransport::new(&session));
+        flasher.set_reset_strategy(crate::reset::ResetStrategy::NoReset);
+        flasher.reset(false)?;
+        // The read times out before the newline but the data is kept.
+        assert!(flasher.read_line(Duration::from_millis(10)).is_err());
+        flasher.write_raw(b"x")?;
+        assert_eq!(flasher.read_line(Duration::from_millis(10))?, b"hello\r\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,803 @@
+// Copyright 2022 Stephen Checkoway
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A simulated ESP device for testing without hardware.
+//!
+//! [`SimulatedDevice`] implements [`Transport`] and behaves like an ESP chip
+//! connected via a USB-serial adapter with the usual auto-reset circuit: RTS
+//! is connected to EN and DTR to GPIO0. Resetting into the bootloader starts
+//! the simulated ROM loader which speaks the serial protocol. Executing code
+//! loaded into RAM via MEM_END starts the simulated stub loader.
+//!
+//! The device's RAM and registers are modeled as a sparse map of words and
+//! its flash as a byte array with NOR flash semantics: erasing sets bytes to
+//! 0xFF and writing can only clear bits.
+
+use std::cell::{Ref, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use flate2::{Decompress, FlushDecompress};
+
+use crate::chip::Chip;
+use crate::transport::Transport;
+use crate::Result;
+
+const CHIP_MAGIC_REG: u32 = 0x40001000;
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+pub(crate) const DEFAULT_FLASH_SIZE: usize = 4 << 20;
+pub(crate) const FLASH_MANUFACTURER_ID: u8 = 0xEF;
+const FLASH_MEMORY_TYPE: u8 = 0x40;
+
+// SPI_CMD_REG
+const SPI_USR: u32 = 1 << 18;
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+// Error codes returned in responses.
+const ERR_INVALID_MESSAGE: u8 = 0x05;
+const ERR_FAILED_TO_ACT: u8 = 0x06;
+const ERR_BAD_DATA_LEN: u8 = 0xC0;
+const ERR_BAD_DATA_CHECKSUM: u8 = 0xC1;
+const ERR_INVALID_COMMAND: u8 = 0xC3;
+const ERR_NOT_IN_FLASH_MODE: u8 = 0xC6;
+const ERR_INFLATE: u8 = 0xC7;
+
+fn magic(chip: Chip) -> u32 {
+    match chip {
+        Chip::Esp8266 => 0xFFF0C101,
+        Chip::Esp32 => 0x00F01D83,
+        Chip::Esp32S2 => 0x000007C6,
+        Chip::Esp32S3 => 0x00000009,
+        Chip::Esp32C3 => 0x1B31506F,
+        Chip::Esp32C2 => 0x6F51306F,
+        Chip::Esp32C6 => 0x2CE0806F,
+        Chip::Esp32H2 => 0xD7B73E80,
+        // The ESP32-P4 is identified by its chip ID.
+        Chip::Esp32P4 => 0,
+    }
+}
+
+// The crystal frequency in Hz.
+fn crystal_frequency(chip: Chip) -> u32 {
+    match chip {
+        Chip::Esp8266 => 26_000_000,
+        _ => chip.crystal_frequencies().last().unwrap() * 1_000_000,
+    }
+}
+
+fn uart_clkdiv(chip: Chip, baud_rate: u32) -> u32 {
+    let divider = chip.uart_clkdiv_reg().map_or(1, |(_, divider)| divider);
+    crystal_frequency(chip) * divider / baud_rate
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    App,
+    RomLoader,
+    Stub,
+}
+
+// An in-progress FLASH_BEGIN or FLASH_DEFL_BEGIN.
+struct FlashWrite {
+    offset: usize,
+    packet_size: usize,
+    position: usize,
+    inflater: Option<Decompress>,
+}
+
+// An in-progress stub READ_FLASH.
+struct FlashRead {
+    offset: usize,
+    len: usize,
+    packet_size: usize,
+    max_pending: usize,
+    sent: usize,
+    acked: usize,
+}
+
+struct Device {
+    chip: Chip,
+    mode: Mode,
+    baud_rate: u32,
+    dtr: bool,
+    rts: bool,
+    memory: HashMap<u32, u32>,
+    flash: Vec<u8>,
+    input: Vec<u8>,
+    in_frame: bool,
+    output: VecDeque<u8>,
+    mem_write: Option<(u32, u32)>,
+    flash_write: Option<FlashWrite>,
+    flash_read: Option<FlashRead>,
+}
+
+impl Device {
+    fn new(chip: Chip, flash_size: usize) -> Self {
+        let mut memory = HashMap::new();
+        memory.insert(CHIP_MAGIC_REG, magic(chip));
+        if chip == Chip::Esp8266 {
+            // The flash size is stored in EFUSE_DATA3_REG.
+            let size_bits = match flash_size {
+                0x200000 => 0,
+                _ => 1,
+            };
+            memory.insert(0x3FF0005C, size_bits << 26);
+        }
+        if let Some((clkdiv_reg, _)) = chip.uart_clkdiv_reg() {
+            memory.insert(clkdiv_reg, uart_clkdiv(chip, 115200));
+        }
+        Device {
+            chip,
+            mode: Mode::App,
+            baud_rate: 115200,
+            dtr: false,
+            rts: false,
+            memory,
+            flash: vec![0xFF; flash_size],
+            input: Vec::new(),
+            in_frame: false,
+            output: VecDeque::new(),
+            mem_write: None,
+            flash_write: None,
+            flash_read: None,
+        }
+    }
+
+    fn set_lines(&mut self, dtr: bool, rts: bool) {
+        let was_in_reset = self.rts;
+        self.dtr = dtr;
+        self.rts = rts;
+        if self.rts {
+            // Holding EN low.
+            self.output.clear();
+        } else if was_in_reset {
+            // Released EN; GPIO0 selects the boot mode.
+            self.boot(self.dtr);
+        }
+    }
+
+    fn boot(&mut self, download: bool) {
+        self.mode = if download { Mode::RomLoader } else { Mode::App };
+        self.input.clear();
+        self.in_frame = false;
+        self.mem_write = None;
+        self.flash_write = None;
+        self.flash_read = None;
+        if self.chip == Chip::Esp8266 {
+            // The ESP8266 prints its boot message at 74880 baud which never
+            // arrives intact.
+            return;
+        }
+        let boot = if download {
+            "0x3 (DOWNLOAD_BOOT(UART0/UART1/SDIO_REI_REO_V2))\r\nwaiting for download\r\n"
+        } else {
+            "0x13 (SPI_FAST_FLASH_BOOT)\r\n"
+        };
+        let message =
+            format!("ets Jun  8 2016 00:22:57\r\n\r\nrst:0x1 (POWERON_RESET),boot:{boot}");
+        self.output.extend(message.as_bytes());
+    }
+
+    fn receive(&mut self, data: &[u8]) {
+        if self.mode == Mode::App {
+            return;
+        }
+        for &byte in data {
+            if byte == SLIP_END {
+                if self.in_frame && !self.input.is_empty() {
+                    let frame = std::mem::take(&mut self.input);
+                    self.handle_frame(&decode_slip(&frame));
+                }
+                self.in_frame = true;
+                self.input.clear();
+            } else if self.in_frame {
+                self.input.push(byte);
+            }
+        }
+    }
+
+    fn send_frame(&mut self, data: &[u8]) {
+        self.output.push_back(SLIP_END);
+        for &byte in data {
+            match byte {
+                SLIP_END => self.output.extend([SLIP_ESC, SLIP_ESC_END]),
+                SLIP_ESC => self.output.extend([SLIP_ESC, SLIP_ESC_ESC]),
+                _ => self.output.push_back(byte),
+            }
+        }
+        self.output.push_back(SLIP_END);
+    }
+
+    fn status_size(&self) -> usize {
+        if self.mode == Mode::RomLoader && self.chip != Chip::Esp8266 {
+            4
+        } else {
+            2
+        }
+    }
+
+    fn respond(&mut self, cmd: u8, value: u32, data: &[u8], error: Option<u8>) {
+        let status_size = self.status_size();
+        let size = (data.len() + status_size) as u16;
+        let mut packet = vec![1, cmd];
+        packet.extend(size.to_le_bytes());
+        packet.extend(value.to_le_bytes());
+        packet.extend(data);
+        match error {
+            None => packet.extend([0, 0]),
+            Some(err) => packet.extend([1, err]),
+        }
+        packet.resize(packet.len() + status_size - 2, 0);
+        self.send_frame(&packet);
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        if self.flash_read.is_some() {
+            self.handle_read_ack(frame);
+            return;
+        }
+        if frame.len() < 8 || frame[0] != 0 {
+            return;
+        }
+        let cmd = frame[1];
+        let len = u16::from_le_bytes([frame[2], frame[3]]) as usize;
+        let checksum = frame[4];
+        let data = &frame[8..];
+        if data.len() != len {
+            self.respond(cmd, 0, &[], Some(ERR_BAD_DATA_LEN));
+            return;
+        }
+        match self.handle_command(cmd, checksum, data) {
+            Ok((value, response)) => {
+                self.respond(cmd, value, &response, None);
+                self.after_command(cmd, data);
+            }
+            Err(err) => self.respond(cmd, 0, &[], Some(err)),
+        }
+    }
+
+    // Side effects that happen after the response has been sent.
+    fn after_command(&mut self, cmd: u8, data: &[u8]) {
+        if data.len() < 4 {
+            return;
+        }
+        match cmd {
+            // MEM_END with execute.
+            0x06 if word(data, 0) == 0 && self.mode == Mode::RomLoader => {
+                self.mode = Mode::Stub;
+                self.send_frame(b"OHAI");
+            }
+            // FLASH_END and FLASH_DEFL_END with reboot.
+            0x04 | 0x12 if word(data, 0) == 0 => self.boot(false),
+            // RUN_USER_CODE.
+            0xD3 => self.boot(false),
+            // CHANGE_BAUDRATE.
+            0x0F => {
+                self.baud_rate = word(data, 0);
+                if let Some((clkdiv_reg, _)) = self.chip.uart_clkdiv_reg() {
+                    let clkdiv = uart_clkdiv(self.chip, self.baud_rate);
+                    self.memory.insert(clkdiv_reg, clkdiv);
+                }
+            }
+            // READ_FLASH.
+            0xD2 if self.flash_read.is_some() => self.send_read_packets(),
+            _ => (),
+        }
+    }
+
+    fn handle_command(
+        &mut self,
+        cmd: u8,
+        checksum: u8,
+        data: &[u8],
+    ) -> std::result::Result<(u32, Vec<u8>), u8> {
+        let is_stub = self.mode == Mode::Stub;
+        let unsupported = if is_stub {
+            ERR_INVALID_COMMAND
+        } else {
+            ERR_INVALID_MESSAGE
+        };
+        let param = |idx: usize| -> std::result::Result<u32, u8> {
+            if data.len() < 4 * idx + 4 {
+                Err(ERR_BAD_DATA_LEN)
+            } else {
+                Ok(word(data, idx))
+            }
+        };
+        match cmd {
+            // SYNC
+            0x08 if !is_stub => {
+                // The ROM loader responds to SYNC multiple times.
+                for _ in 0..7 {
+                    self.respond(cmd, 0, &[], None);
+                }
+                Ok((0, Vec::new()))
+            }
+            // FLASH_BEGIN and FLASH_DEFL_BEGIN
+            0x02 | 0x10 => {
+                if cmd == 0x10 && self.chip == Chip::Esp8266 && !is_stub {
+                    return Err(unsupported);
+                }
+                let erase_size = param(0)? as usize;
+                let packet_size = param(2)? as usize;
+                let offset = param(3)? as usize;
+                let erase_size = if self.chip == Chip::Esp8266 && !is_stub {
+                    esp8266_rom_erased_size(offset, erase_size)
+                } else {
+                    erase_size
+                };
+                self.erase(offset, erase_size)?;
+                self.flash_write = Some(FlashWrite {
+                    offset,
+                    packet_size,
+                    position: offset,
+                    inflater: (cmd == 0x10).then(|| Decompress::new(false)),
+                });
+                Ok((0, Vec::new()))
+            }
+            // FLASH_DATA and FLASH_DEFL_DATA
+            0x03 | 0x11 => {
+                let payload = checked_payload(data, checksum)?;
+                let sequence_num = param(1)? as usize;
+                let mut write = self.flash_write.take().ok_or(ERR_NOT_IN_FLASH_MODE)?;
+                let result = match write.inflater {
+                    None => {
+                        let start = write.offset + sequence_num * write.packet_size;
+                        self.write_flash(start, payload)
+                    }
+                    Some(ref mut inflater) => {
+                        let mut output = Vec::with_capacity(4 * payload.len() + 1024);
+                        let mut input = payload;
+                        loop {
+                            let before = inflater.total_in();
+                            inflater
+                                .decompress_vec(input, &mut output, FlushDecompress::None)
+                                .map_err(|_| ERR_INFLATE)?;
+                            input = &input[(inflater.total_in() - before) as usize..];
+                            if input.is_empty() && output.len() < output.capacity() {
+                                break;
+                            }
+                            output.reserve(output.capacity());
+                        }
+                        let start = write.position;
+                        write.position += output.len();
+                        self.write_flash(start, &output)
+                    }
+                };
+                self.flash_write = Some(write);
+                result.map(|_| (0, Vec::new()))
+            }
+            // FLASH_END and FLASH_DEFL_END; the stub requires a preceding begin.
+            0x04 | 0x12 => {
+                let write = self.flash_write.take();
+                if is_stub && write.is_none() {
+                    return Err(ERR_NOT_IN_FLASH_MODE);
+                }
+                Ok((0, Vec::new()))
+            }
+            // MEM_BEGIN
+            0x05 => {
+                self.mem_write = Some((param(3)?, param(2)?));
+                Ok((0, Vec::new()))
+            }
+            // MEM_END
+            0x06 => {
+                self.mem_write = None;
+                Ok((0, Vec::new()))
+            }
+            // MEM_DATA
+            0x07 => {
+                let payload = checked_payload(data, checksum)?;
+                let sequence_num = param(1)?;
+                let (offset, packet_size) = self.mem_write.ok_or(ERR_FAILED_TO_ACT)?;
+                let start = offset + sequence_num * packet_size;
+                for (idx, &byte) in payload.iter().enumerate() {
+                    self.write_byte(start + idx as u32, byte);
+                }
+                Ok((0, Vec::new()))
+            }
+            // WRITE_REG
+            0x09 => {
+                let (address, value, mask) = (param(0)?, param(1)?, param(2)?);
+                let old = self.read_reg(address);
+                self.write_reg(address, (old & !mask) | (value & mask));
+                Ok((0, Vec::new()))
+            }
+            // READ_REG
+            0x0A => Ok((self.read_reg(param(0)?), Vec::new())),
+            // SPI_SET_PARAMS and SPI_ATTACH
+            0x0B | 0x0D => Ok((0, Vec::new())),
+            // CHANGE_BAUDRATE
+            0x0F => Ok((0, Vec::new())),
+            // SPI_FLASH_MD5
+            0x13 if is_stub || self.chip != Chip::Esp8266 => {
+                let address = param(0)? as usize;
+                let size = param(1)? as usize;
+                let region = self
+                    .flash
+                    .get(address..address + size)
+                    .ok_or(ERR_FAILED_TO_ACT)?;
+                let digest = md5::compute(region);
+                if is_stub {
+                    Ok((0, digest.0.to_vec()))
+                } else {
+                    Ok((0, format!("{digest:x}").into_bytes()))
+                }
+            }
+            // GET_SECURITY_INFO
+            0x14 if !matches!(self.chip, Chip::Esp8266 | Chip::Esp32) => {
+                // Nothing is enabled and all of the keys are unused.
+                let mut info = vec![0u8; 12];
+                if self.chip != Chip::Esp32S2 {
+                    info.extend((self.chip.image_chip_id() as u32).to_le_bytes());
+                    info.extend(0u32.to_le_bytes());
+                }
+                Ok((0, info))
+            }
+            // ERASE_FLASH
+            0xD0 if is_stub => {
+                self.flash.fill(0xFF);
+                Ok((0, Vec::new()))
+            }
+            // ERASE_REGION
+            0xD1 if is_stub => {
+                let offset = param(0)? as usize;
+                let size = param(1)? as usize;
+                if offset % FLASH_SECTOR_SIZE != 0 || size % FLASH_SECTOR_SIZE != 0 {
+                    return Err(ERR_FAILED_TO_ACT);
+                }
+                self.erase(offset, size)?;
+                Ok((0, Vec::new()))
+            }
+            // READ_FLASH
+            0xD2 if is_stub => {
+                let offset = param(0)? as usize;
+                let len = param(1)? as usize;
+                if offset + len > self.flash.len() {
+                    return Err(ERR_FAILED_TO_ACT);
+                }
+                self.flash_read = Some(FlashRead {
+                    offset,
+                    len,
+                    packet_size: param(2)? as usize,
+                    max_pending: param(3)? as usize,
+                    sent: 0,
+                    acked: 0,
+                });
+                Ok((0, Vec::new()))
+            }
+            // RUN_USER_CODE
+            0xD3 if is_stub => Ok((0, Vec::new())),
+            _ => Err(unsupported),
+        }
+    }
+
+    fn send_read_packets(&mut self) {
+        let read = self.flash_read.as_mut().unwrap();
+        let mut packets: Vec<(usize, usize)> = Vec::new();
+        while read.sent < read.len && read.sent - read.acked < read.max_pending * read.packet_size {
+            let size = (read.len - read.sent).min(read.packet_size);
+            packets.push((read.offset + read.sent, size));
+            read.sent += size;
+        }
+        for (start, size) in packets {
+            let data = self.flash[start..start + size].to_vec();
+            self.send_frame(&data);
+        }
+    }
+
+    fn handle_read_ack(&mut self, frame: &[u8]) {
+        let read = self.flash_read.as_mut().unwrap();
+        if frame.len() != 4 {
+            // The real stub would abort the transfer.
+            self.flash_read = None;
+            return;
+        }
+        read.acked = word(frame, 0) as usize;
+        if read.acked < read.len {
+            self.send_read_packets();
+            return;
+        }
+        let read = self.flash_read.take().unwrap();
+        let digest = md5::compute(&self.flash[read.offset..read.offset + read.len]);
+        self.send_frame(&digest.0);
+    }
+
+    fn erase(&mut self, offset: usize, size: usize) -> std::result::Result<(), u8> {
+        if size == 0 {
+            return Ok(());
+        }
+        let start = offset - offset % FLASH_SECTOR_SIZE;
+        let end = (offset + size + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE * FLASH_SECTOR_SIZE;
+        self.flash
+            .get_mut(start..end)
+            .ok_or(ERR_FAILED_TO_ACT)?
+            .fill(0xFF);
+        Ok(())
+    }
+
+    fn write_flash(&mut self, offset: usize, data: &[u8]) -> std::result::Result<(), u8> {
+        let region = self
+            .flash
+            .get_mut(offset..offset + data.len())
+            .ok_or(ERR_FAILED_TO_ACT)?;
+        // Writing can only clear bits.
+        for (dest, &src) in region.iter_mut().zip(data) {
+            *dest &= src;
+        }
+        Ok(())
+    }
+
+    fn read_reg(&self, address: u32) -> u32 {
+        self.memory.get(&(address & !3)).copied().unwrap_or(0)
+    }
+
+    fn write_reg(&mut self, address: u32, value: u32) {
+        let address = address & !3;
+        self.memory.insert(address, value);
+        let regs = self.chip.spi_regs();
+        if address == regs.cmd && value & SPI_USR != 0 {
+            self.spi_command();
+        }
+    }
+
+    fn write_byte(&mut self, address: u32, byte: u8) {
+        let shift = 8 * (address & 3);
+        let word = self.memory.entry(address & !3).or_insert(0);
+        *word = (*word & !(0xFF << shift)) | ((byte as u32) << shift);
+    }
+
+    // Execute the SPI command set up in the SPI registers. Only reading the
+    // flash ID is supported; other commands complete without effect.
+    fn spi_command(&mut self) {
+        let regs = self.chip.spi_regs();
+        let command = self.read_reg(regs.user2) & 0xFFFF;
+        if command == 0x9F {
+            let capacity = self.flash.len().trailing_zeros();
+            let id =
+                FLASH_MANUFACTURER_ID as u32 | (FLASH_MEMORY_TYPE as u32) << 8 | capacity << 16;
+            self.memory.insert(regs.w0, id);
+        }
+        let cmd = self.read_reg(regs.cmd);
+        self.memory.insert(regs.cmd, cmd & !SPI_USR);
+    }
+}
+
+#[inline]
+fn word(data: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes(data[4 * idx..4 * idx + 4].try_into().unwrap())
+}
+
+// Returns the data following the 16-byte header of the *_DATA commands after
+// checking its length and checksum.
+fn checked_payload(data: &[u8], checksum: u8) -> std::result::Result<&[u8], u8> {
+    if data.len() < 16 || word(data, 0) as usize != data.len() - 16 {
+        return Err(ERR_BAD_DATA_LEN);
+    }
+    let payload = &data[16..];
+    if payload.iter().fold(0xEF, |sum, x| sum ^ x) != checksum {
+        return Err(ERR_BAD_DATA_CHECKSUM);
+    }
+    Ok(payload)
+}
+
+fn decode_slip(frame: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(frame.len());
+    let mut escaped = false;
+    for &byte in frame {
+        match (escaped, byte) {
+            (false, SLIP_ESC) => escaped = true,
+            (true, SLIP_ESC_END) => {
+                output.push(SLIP_END);
+                escaped = false;
+            }
+            (true, SLIP_ESC_ESC) => {
+                output.push(SLIP_ESC);
+                escaped = false;
+            }
+            (_, byte) => {
+                output.push(byte);
+                escaped = false;
+            }
+        }
+    }
+    output
+}
+
+// The number of bytes the ESP8266 ROM loader actually erases when asked to
+// erase `size` bytes at `offset`. It erases the sectors up to the next 64 kB
+// block boundary twice over.
+fn esp8266_rom_erased_size(offset: usize, size: usize) -> usize {
+    const SECTORS_PER_BLOCK: usize = 16;
+    let num_sectors = (size + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
+    let head_sectors = SECTORS_PER_BLOCK - (offset / FLASH_SECTOR_SIZE) % SECTORS_PER_BLOCK;
+    (num_sectors + num_sectors.min(head_sectors)) * FLASH_SECTOR_SIZE
+}
+
+/// A simulated ESP device.
+///
+/// Cloning a `SimulatedDevice` produces another handle to the same device so
+/// one handle can be given to a [`Flasher`](crate::Flasher) while another is
+/// used to inspect the device.
+#[derive(Clone)]
+pub struct SimulatedDevice(Rc<RefCell<Device>>);
+
+impl SimulatedDevice {
+    /// Create a simulated `chip` with 4 MB of erased flash.
+    pub fn new(chip: Chip) -> Self {
+        Self::with_flash_size(chip, DEFAULT_FLASH_SIZE)
+    }
+
+    /// Create a simulated `chip` with `flash_size` bytes of erased flash.
+    /// The size must be a power of two.
+    pub fn with_flash_size(chip: Chip, flash_size: usize) -> Self {
+        assert!(flash_size.is_power_of_two(), "Invalid flash size");
+        SimulatedDevice(Rc::new(RefCell::new(Device::new(chip, flash_size))))
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.0.borrow().chip
+    }
+
+    /// The contents of the flash.
+    pub fn flash(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.0.borrow(), |device| device.flash.as_slice())
+    }
+
+    /// Replace the contents of the flash at `offset` with `data`.
+    pub fn set_flash(&self, offset: usize, data: &[u8]) {
+        self.0.borrow_mut().flash[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    pub fn read_memory(&self, address: u32) -> u32 {
+        self.0.borrow().read_reg(address)
+    }
+
+    pub fn write_memory(&self, address: u32, value: u32) {
+        self.0.borrow_mut().memory.insert(address & !3, value);
+    }
+
+    /// Returns true if the simulated stub loader is running.
+    pub fn is_stub_running(&self) -> bool {
+        self.0.borrow().mode == Mode::Stub
+    }
+
+    /// Returns true if the device is running its application rather than a
+    /// loader.
+    pub fn is_app_running(&self) -> bool {
+        self.0.borrow().mode == Mode::App
+    }
+}
+
+impl Transport for SimulatedDevice {
+    fn read(&mut self, buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+        // The device responds instantly so if there's no data, there won't be
+        // any before the timeout.
+        let mut device = self.0.borrow_mut();
+        if device.output.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+        let size = buf.len().min(device.output.len());
+        for (dest, src) in buf.iter_mut().zip(device.output.drain(..size)) {
+            *dest = src;
+        }
+        Ok(size)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.borrow_mut().receive(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn baud_rate(&self) -> Result<u32> {
+        Ok(self.0.borrow().baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<()> {
+        self.0.borrow_mut().baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> Result<()> {
+        let mut device = self.0.borrow_mut();
+        let rts = device.rts;
+        device.set_lines(level, rts);
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> Result<()> {
+        let mut device = self.0.borrow_mut();
+        let dtr = device.dtr;
+        device.set_lines(dtr, level);
+        Ok(())
+    }
+
+    fn write_dtr_rts(&mut self, dtr: bool, rts: bool) -> Result<()> {
+        self.0.borrow_mut().set_lines(dtr, rts);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.0.borrow_mut().output.clear();
+        Ok(())
+    }
+}
+
+/// Helpers for tests that run a [`Flasher`](crate::Flasher) against a
+/// simulated device.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::SimulatedDevice;
+    use crate::{Chip, Flasher, Result};
+
+    pub(crate) const ALL_CHIPS: [Chip; 9] = [
+        Chip::Esp8266,
+        Chip::Esp32,
+        Chip::Esp32S2,
+        Chip::Esp32S3,
+        Chip::Esp32C3,
+        Chip::Esp32C2,
+        Chip::Esp32C6,
+        Chip::Esp32H2,
+        Chip::Esp32P4,
+    ];
+
+    // A stub image that the simulated loader can run.
+    pub(crate) fn stub_image(chip: Chip) -> Vec<u8> {
+        let chip_id = match chip {
+            Chip::Esp8266 => 0x10000,
+            _ => chip.image_chip_id() as u32,
+        };
+        let mut image = b"STUB".to_vec();
+        for value in [chip_id, 0x4009_0000, 0x4009_0000, 8] {
+            image.extend(u32::to_le_bytes(value));
+        }
+        image.extend(b"stubtext");
+        for value in [0x3FFB_0000u32, 4] {
+            image.extend(value.to_le_bytes());
+        }
+        image.extend(b"data");
+        image
+    }
+
+    pub(crate) fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|x| (x * 7 + x / 251) as u8).collect()
+    }
+
+    // Connect to a new simulated device, running the stub loader if `stub` is
+    // true.
+    pub(crate) fn connect(chip: Chip, stub: bool) -> Result<(SimulatedDevice, Flasher)> {
+        let device = SimulatedDevice::new(chip);
+        let mut flasher = Flasher::with_transport(device.clone());
+        assert_eq!(flasher.connect()?, chip);
+        if stub {
+            flasher.run_stub(&stub_image(chip))?;
+            assert!(device.is_stub_running());
+        }
+        Ok((device, flasher))
+    }
+}
@@ -12,33 +12,236 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use binrw::binread;
+//! Flasher stubs in the `STUB` binary format, which can be converted from the
+//! JSON files shipped with esptool or from an ELF build of the stub.
+//!
+//! All values in the binary format are little endian:
+//!
+//! ```text
+//! 00:  Magic value: 'STUB'
+//! 04:  Chip type
+//! 08:  Entry address
+//! 0C:  Text start
+//! 10:  Text length
+//! 14:  Text
+//! n:   Data start
+//! n+4: Data length
+//! n+8: Data
+//! ```
 
-use crate::Chip;
+use std::fmt;
+use std::io::Cursor;
 
-#[binread]
-#[br(little, magic = b"STUB")]
+use binrw::{binrw, BinRead, BinWrite};
+use serde::Deserialize;
+
+use crate::elf;
+use crate::{Chip, Error, Result};
+
+// The chip type used for the ESP8266, which has no image chip ID.
+const ESP8266_CHIP: u32 = 0x10000;
+
+#[binrw]
+#[brw(little, magic = b"STUB")]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Stub {
     pub chip: u32,
     pub entry: u32,
     pub text_start: u32,
     #[br(temp)]
+    #[bw(calc = text.len() as u32)]
     text_len: u32,
     #[br(count = text_len)]
     pub text: Vec<u8>,
     pub data_start: u32,
     #[br(temp)]
+    #[bw(calc = data.len() as u32)]
     data_len: u32,
     #[br(count = data_len)]
     pub data: Vec<u8>,
 }
 
+// The format of the stub JSON files in esptool's `targets/stub_flasher`
+// directory. The text and data are base64 encoded.
+#[derive(Deserialize)]
+struct JsonStub {
+    entry: u32,
+    text: String,
+    text_start: u32,
+    #[serde(default)]
+    data: String,
+    #[serde(default)]
+    data_start: u32,
+}
+
 impl Stub {
+    pub fn new(chip: Chip) -> Self {
+        Stub {
+            chip: match chip {
+                Chip::Esp8266 => ESP8266_CHIP,
+                _ => chip.image_chip_id() as u32,
+            },
+            entry: 0,
+            text_start: 0,
+            text: Vec::new(),
+            data_start: 0,
+            data: Vec::new(),
+        }
+    }
+
+    /// Parse a stub in the `STUB` binary format.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Ok(Stub::read(&mut Cursor::new(data))?)
+    }
+
+    /// Parse one of esptool's JSON stub files. These do not record the chip
+    /// so it must be supplied.
+    pub fn from_json(chip: Chip, json: &str) -> Result<Self> {
+        let json: JsonStub = serde_json::from_str(json)
+            .map_err(|err| Error::FormatError(format!("Invalid stub JSON: {err}")))?;
+        let decode = |field: &str, value: &str| {
+            base64::decode(value)
+                .map_err(|err| Error::FormatError(format!("Invalid stub {field}: {err}")))
+        };
+        Ok(Stub {
+            entry: json.entry,
+            text_start: json.text_start,
+            text: decode("text", &json.text)?,
+            data_start: json.data_start,
+            data: decode("data", &json.data)?,
+            ..Stub::new(chip)
+        })
+    }
+
+    /// Extract a stub from an ELF build. The executable segment is the text
+    /// and the remaining segment with contents, if any, is the data.
+    pub fn from_elf(chip: Chip, data: &[u8]) -> Result<Self> {
+        let (entry, segments) = elf::load_segments(data)?;
+        let mut stub = Stub {
+            entry,
+            ..Stub::new(chip)
+        };
+        let (mut have_text, mut have_data) = (false, false);
+        for segment in segments.iter().filter(|segment| !segment.data.is_empty()) {
+            let (have, start, contents) = if segment.is_executable() {
+                (&mut have_text, &mut stub.text_start, &mut stub.text)
+            } else {
+                (&mut have_data, &mut stub.data_start, &mut stub.data)
+            };
+            if *have {
+                return Err(Error::FormatError(
+                    "Stub ELF file has too many loadable segments".into(),
+                ));
+            }
+            *have = true;
+            *start = segment.addr;
+            contents.extend(segment.data);
+        }
+        if !have_text {
+            return Err(Error::FormatError(
+                "Stub ELF file has no executable segment".into(),
+            ));
+        }
+        Ok(stub)
+    }
+
+    /// Serialize the stub in the `STUB` binary format.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.write_to(&mut Cursor::new(&mut data))?;
+        Ok(data)
+    }
+
     pub fn chip(&self) -> Option<Chip> {
         match self.chip {
             0..=0xFFFF => Chip::try_from_image_chip_id(self.chip as u16),
-            0x10000 => Some(Chip::Esp8266),
+            ESP8266_CHIP => Some(Chip::Esp8266),
             _ => None,
         }
     }
 }
+
+impl fmt::Display for Stub {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.chip() {
+            Some(chip) => writeln!(f, "Chip: {chip}")?,
+            None => writeln!(f, "Chip: unknown (0x{:X})", self.chip)?,
+        }
+        writeln!(f, "Entry: 0x{:08X}", self.entry)?;
+        writeln!(
+            f,
+            "Text: 0x{:08X} ({} bytes)",
+            self.text_start,
+            self.text.len()
+        )?;
+        write!(
+            f,
+            "Data: 0x{:08X} ({} bytes)",
+            self.data_start,
+            self.data.len()
+        )
+    }
+}
+
+// The stubs generated by stub/stub.py are embedded when the `bundled-stubs`
+// feature is enabled. The build script requires a stub for every chip.
+#[cfg(feature = "bundled-stubs")]
+pub fn bundled(chip: Chip) -> Option<&'static [u8]> {
+    include!(concat!(env!("OUT_DIR"), "/bundled_stubs.rs"))
+}
+
+#[cfg(not(feature = "bundled-stubs"))]
+pub fn bundled(_chip: Chip) -> Option<&'static [u8]> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "bundled-stubs")]
+    #[test]
+    fn test_bundled_stub() -> Result<()> {
+        use crate::sim::test_util::{connect, ALL_CHIPS};
+
+        for chip in ALL_CHIPS {
+            let (device, mut flasher) = connect(chip, false)?;
+            assert!(bundled(chip).is_some());
+            assert!(flasher.run_bundled_stub()?);
+            assert!(device.is_stub_running());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_stub() -> Result<()> {
+        let json = r#"{
+            "entry": 1074521580,
+            "text": "AAECAw==",
+            "text_start": 1074520064,
+            "data": "BAUG",
+            "data_start": 1073537704,
+            "bss_start": 1073528832
+        }"#;
+        let stub = Stub::from_json(Chip::Esp32C3, json)?;
+        assert_eq!(stub.chip(), Some(Chip::Esp32C3));
+        assert_eq!(stub.entry, 0x400BE5EC);
+        assert_eq!(stub.text, [0, 1, 2, 3]);
+        assert_eq!(stub.data_start, 0x3FFCE2A8);
+        assert_eq!(stub.data, [4, 5, 6]);
+
+        let bytes = stub.to_bytes()?;
+        assert_eq!(&bytes[..8], b"STUB\x05\x00\x00\x00");
+        assert_eq!(bytes.len(), 4 + 6 * 4 + 4 + 3);
+        assert_eq!(Stub::from_bytes(&bytes)?, stub);
+
+        let stub = Stub::from_json(
+            Chip::Esp8266,
+            r#"{"entry": 1, "text": "", "text_start": 2}"#,
+        )?;
+        assert_eq!(stub.chip, 0x10000);
+        assert!(stub.data.is_empty());
+        assert!(Stub::from_json(Chip::Esp32, r#"{"entry": 1, "text": "!"}"#).is_err());
+        Ok(())
+    }
+}
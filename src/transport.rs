@@ -0,0 +1,227 @@
+// Copyright 2022 Stephen Checkoway
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+use crate::Result;
+
+// Serial ports use the same timeout for reads and writes.
+const SERIAL_WRITE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// A connection to an ESP device.
+///
+/// A transport carries the bytes to and from the device and controls the
+/// serial parameters and the DTR and RTS lines used to reset the device.
+/// Transports that have no notion of some of these (e.g., a TCP socket has no
+/// baud rate) should ignore them.
+pub trait Transport {
+    /// Read into `buf`, waiting at most `timeout` for data to arrive. Returns
+    /// the number of bytes read. If no data arrives before the timeout, an
+    /// error of kind `io::ErrorKind::TimedOut` is returned.
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+
+    /// Write all of `data`.
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Wait until all written data has been transmitted.
+    fn flush(&mut self) -> io::Result<()>;
+
+    fn baud_rate(&self) -> Result<u32>;
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<()>;
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> Result<()>;
+
+    fn write_request_to_send(&mut self, level: bool) -> Result<()>;
+
+    /// Set DTR and RTS at the same time. Transports which cannot change both
+    /// lines at once set DTR and then RTS.
+    fn write_dtr_rts(&mut self, dtr: bool, rts: bool) -> Result<()> {
+        self.write_data_terminal_ready(dtr)?;
+        self.write_request_to_send(rts)
+    }
+
+    /// Discard any buffered input and output.
+    fn clear(&mut self) -> Result<()>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.set_timeout(timeout)?;
+        Read::read(self.as_mut(), buf)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.set_timeout(SERIAL_WRITE_TIMEOUT)?;
+        Write::write_all(self.as_mut(), data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self.as_mut())
+    }
+
+    fn baud_rate(&self) -> Result<u32> {
+        Ok(self.as_ref().baud_rate()?)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<()> {
+        Ok(self.as_mut().set_baud_rate(baud_rate)?)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> Result<()> {
+        Ok(self.as_mut().write_data_terminal_ready(level)?)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> Result<()> {
+        Ok(self.as_mut().write_request_to_send(level)?)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        Ok(self.as_mut().clear(serialport::ClearBuffer::All)?)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for serialport::TTYPort {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        SerialPort::set_timeout(self, timeout)?;
+        Read::read(self, buf)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        SerialPort::set_timeout(self, SERIAL_WRITE_TIMEOUT)?;
+        Write::write_all(self, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+
+    fn baud_rate(&self) -> Result<u32> {
+        Ok(SerialPort::baud_rate(self)?)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<()> {
+        Ok(SerialPort::set_baud_rate(self, baud_rate)?)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> Result<()> {
+        Ok(SerialPort::write_data_terminal_ready(self, level)?)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> Result<()> {
+        Ok(SerialPort::write_request_to_send(self, level)?)
+    }
+
+    fn write_dtr_rts(&mut self, dtr: bool, rts: bool) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.as_raw_fd();
+        let mut bits: libc::c_int = 0;
+        // SAFETY: `fd` is an open terminal and `bits` outlives both calls.
+        unsafe {
+            if libc::ioctl(fd, libc::TIOCMGET, &mut bits) < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            for (line, level) in [(libc::TIOCM_DTR, dtr), (libc::TIOCM_RTS, rts)] {
+                if level {
+                    bits |= line;
+                } else {
+                    bits &= !line;
+                }
+            }
+            if libc::ioctl(fd, libc::TIOCMSET, &bits) < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        Ok(SerialPort::clear(self, serialport::ClearBuffer::All)?)
+    }
+}
+
+/// A raw TCP connection to a serial port server such as `ser2net` in raw mode.
+///
+/// The server controls the baud rate and the modem control lines so changing
+/// the baud rate only changes the rate reported by `baud_rate()` and setting
+/// DTR and RTS does nothing. As a result, the device cannot be reset.
+pub struct TcpTransport {
+    stream: TcpStream,
+    baud_rate: u32,
+}
+
+impl TcpTransport {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport {
+            stream,
+            baud_rate: 115200,
+        })
+    }
+}
+
+// Sockets report timeouts as `WouldBlock` on some platforms.
+pub(crate) fn read_stream<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    match reader.read(buf) {
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, err))
+        }
+        result => result,
+    }
+}
+
+impl Transport for TcpTransport {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        // A zero timeout is an error.
+        let timeout = timeout.max(Duration::from_millis(1));
+        self.stream.set_read_timeout(Some(timeout))?;
+        read_stream(&mut self.stream, buf)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+
+    fn baud_rate(&self) -> Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
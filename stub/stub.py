@@ -4,6 +4,8 @@
 Extract the flasher stubs from esptool.py.
 
 Usage: IDF_PATH=/path/to/esp-idf ./stub.py
+
+Run this in the stub directory before building with the bundled-stubs feature.
 """
 
 import os
@@ -82,3 +84,11 @@ with open("esp32s3_stub.bin", "wb") as f:
     f.write(image(esptool.ESP32S3ROM.STUB_CODE, 9))
 with open("esp32c3_stub.bin", "wb") as f:
     f.write(image(esptool.ESP32C3ROM.STUB_CODE, 5))
+with open("esp32c2_stub.bin", "wb") as f:
+    f.write(image(esptool.ESP32C2ROM.STUB_CODE, 12))
+with open("esp32c6_stub.bin", "wb") as f:
+    f.write(image(esptool.ESP32C6ROM.STUB_CODE, 13))
+with open("esp32h2_stub.bin", "wb") as f:
+    f.write(image(esptool.ESP32H2ROM.STUB_CODE, 16))
+with open("esp32p4_stub.bin", "wb") as f:
+    f.write(image(esptool.ESP32P4ROM.STUB_CODE, 18))